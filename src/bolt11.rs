@@ -0,0 +1,124 @@
+use anyhow::{anyhow, Error};
+use bitcoin::{
+    bech32::{self, u5, ToBase32, Variant},
+    hashes::{sha256, Hash},
+};
+
+const CHARSET: &str = "qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+
+// feature bits: var_onion_optin (compulsory), payment_secret (compulsory), basic_mpp (optional)
+const FEATURES: u64 = (1 << 8) | (1 << 14) | (1 << 17);
+
+pub enum InvoiceDescription {
+    Direct(String),
+    Hash(String),
+}
+
+/// An invoice we create ourselves because lightningd must not know the preimage.
+/// It is encoded without a valid signature and then signed by lightningd via `signinvoice`.
+pub struct UnsignedInvoice {
+    pub network: String,
+    // amountless invoices leave it out
+    pub amount_msat: Option<u64>,
+    pub timestamp: u64,
+    pub payment_hash: [u8; 32],
+    pub payment_secret: [u8; 32],
+    pub description: InvoiceDescription,
+    pub expiry: u64,
+    pub min_final_cltv_expiry: u64,
+}
+
+impl UnsignedInvoice {
+    pub fn encode(&self) -> Result<String, Error> {
+        let hrp = format!(
+            "ln{}{}",
+            network_prefix(&self.network)?,
+            self.amount_msat
+                .map(encode_amount)
+                .transpose()?
+                .unwrap_or_default()
+        );
+
+        let mut data = to_u5_padded(self.timestamp, 7)?;
+        push_field(&mut data, 1, self.payment_hash.to_base32())?;
+        push_field(&mut data, 16, self.payment_secret.to_base32())?;
+        match &self.description {
+            InvoiceDescription::Direct(d) => push_field(&mut data, 13, d.as_bytes().to_base32())?,
+            InvoiceDescription::Hash(d) => push_field(
+                &mut data,
+                23,
+                sha256::Hash::hash(d.as_bytes()).into_inner().to_base32(),
+            )?,
+        }
+        push_field(&mut data, 6, to_u5_minimal(self.expiry)?)?;
+        push_field(&mut data, 24, to_u5_minimal(self.min_final_cltv_expiry)?)?;
+        push_field(&mut data, 5, to_u5_padded(FEATURES, 4)?)?;
+        // placeholder for the 65 byte recoverable signature, replaced by `signinvoice`
+        data.extend([0u8; 65].to_base32());
+
+        bech32::encode(&hrp, data, Variant::Bech32)
+            .map_err(|e| anyhow!("Error encoding invoice: {}", e))
+    }
+}
+
+/// Read the creation timestamp of a bolt11 invoice without verifying it.
+pub fn timestamp(bolt11: &str) -> Option<u64> {
+    let bolt11 = bolt11.to_lowercase();
+    let (_, data) = bolt11.rsplit_once('1')?;
+    if data.len() < 7 {
+        return None;
+    }
+    let mut timestamp = 0;
+    for c in data.chars().take(7) {
+        timestamp = (timestamp << 5) | CHARSET.find(c)? as u64;
+    }
+    Some(timestamp)
+}
+
+fn network_prefix(network: &str) -> Result<&'static str, Error> {
+    match network {
+        "bitcoin" => Ok("bc"),
+        "testnet" => Ok("tb"),
+        "signet" => Ok("tbs"),
+        "regtest" => Ok("bcrt"),
+        other => Err(anyhow!("Unsupported network for hold invoices: {}", other)),
+    }
+}
+
+fn encode_amount(amount_msat: u64) -> Result<String, Error> {
+    let pico = amount_msat
+        .checked_mul(10)
+        .ok_or_else(|| anyhow!("amount too large for an invoice: {}msat", amount_msat))?;
+    for (multiplier, unit) in [(1_000_000_000, "m"), (1_000_000, "u"), (1_000, "n")] {
+        if pico % multiplier == 0 {
+            return Ok(format!("{}{}", pico / multiplier, unit));
+        }
+    }
+    Ok(format!("{}p", pico))
+}
+
+fn push_field(data: &mut Vec<u5>, tag: u8, field: Vec<u5>) -> Result<(), Error> {
+    if field.len() >= 1024 {
+        return Err(anyhow!("invoice field too long"));
+    }
+    data.push(u5::try_from_u8(tag)?);
+    data.extend(to_u5_padded(field.len() as u64, 2)?);
+    data.extend(field);
+    Ok(())
+}
+
+fn to_u5_padded(value: u64, len: usize) -> Result<Vec<u5>, Error> {
+    let mut words = Vec::with_capacity(len);
+    for i in (0..len).rev() {
+        words.push(u5::try_from_u8(((value >> (5 * i)) & 31) as u8)?);
+    }
+    Ok(words)
+}
+
+fn to_u5_minimal(value: u64) -> Result<Vec<u5>, Error> {
+    let mut len = 1;
+    while len < 13 && value >> (5 * len) != 0 {
+        len += 1;
+    }
+    to_u5_padded(value, len)
+}
@@ -0,0 +1,215 @@
+use std::{process::Stdio, time::Duration};
+
+use anyhow::{anyhow, Error};
+use cln_rpc::model::DatastoreMode;
+use log::{info, warn};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    process::Command,
+    time,
+};
+
+use crate::{
+    config::PluginState, datastore, deldatastore, events::HodlEvent, listdatastore, PLUGIN_NAME,
+};
+
+// give up on a single delivery after this long
+const CALLBACK_TIMEOUT_SECONDS: u64 = 30;
+// wait this long before retrying a failed delivery, doubling up to `MAX_RETRY_SECONDS`
+const FIRST_RETRY_SECONDS: u64 = 1;
+const MAX_RETRY_SECONDS: u64 = 300;
+
+/// Where the hold-invoice events are delivered to.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Callback {
+    /// POST the event to a plain http URL.
+    Url(HttpUrl),
+    /// Run an executable with the event on stdin.
+    Exec(String),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct HttpUrl {
+    pub host: String,
+    pub port: u16,
+    pub path: String,
+}
+impl HttpUrl {
+    pub fn parse(url: &str) -> Result<HttpUrl, Error> {
+        let rest = url
+            .strip_prefix("http://")
+            .ok_or(anyhow!("only http:// URLs are supported: {}", url))?;
+        let (authority, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+        // the port follows the last colon, unless it is part of an IPv6 address
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) if !port.ends_with(']') => (
+                host,
+                port.parse::<u16>()
+                    .map_err(|e| anyhow!("invalid port in {}: {}", url, e))?,
+            ),
+            _ => (authority, 80),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            return Err(anyhow!("missing host in {}", url));
+        }
+        Ok(HttpUrl {
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+}
+impl std::fmt::Display for HttpUrl {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "http://[{}]:{}{}", self.host, self.port, self.path)
+        } else {
+            write!(f, "http://{}:{}{}", self.host, self.port, self.path)
+        }
+    }
+}
+
+fn outbox_key(index: Option<u64>) -> Vec<String> {
+    let mut key = vec![PLUGIN_NAME.to_string(), "outbox".to_string()];
+    if let Some(index) = index {
+        // zero-padded so the datastore lists them in order, the random suffix keeps
+        // two events apart even if they got the same index
+        let suffix: [u8; 8] = rand::random();
+        key.push(format!("{:020}-{}", index, hex::encode(suffix)));
+    }
+    key
+}
+
+/// Persist `event` until the callback got it, does nothing without a callback.
+pub async fn queue_callback(state: &PluginState, event: &HodlEvent) -> Result<(), Error> {
+    if state.config.lock().callback().is_none() {
+        return Ok(());
+    }
+    datastore(
+        state.rpc.as_ref(),
+        outbox_key(Some(event.index)),
+        Some(serde_json::to_string(event)?),
+        None,
+        Some(DatastoreMode::MUST_CREATE),
+        None,
+    )
+    .await?;
+    state.outbox.notify_one();
+    Ok(())
+}
+
+/// Deliver the queued events in the background, oldest first, including the
+/// ones left over from before a restart.
+pub fn start_callbacks(state: &PluginState) {
+    let callback = match state.config.lock().callback() {
+        Some(callback) => callback,
+        None => return,
+    };
+    let state = state.clone();
+    tokio::spawn(async move {
+        loop {
+            match deliver_outbox(&state, &callback).await {
+                Ok(()) => state.outbox.notified().await,
+                Err(e) => {
+                    warn!("Error reading the callback outbox: {}", e);
+                    time::sleep(Duration::from_secs(MAX_RETRY_SECONDS)).await;
+                }
+            }
+        }
+    });
+}
+
+async fn deliver_outbox(state: &PluginState, callback: &Callback) -> Result<(), Error> {
+    let rpc = state.rpc.as_ref();
+    let mut entries = listdatastore(rpc, Some(outbox_key(None))).await?.datastore;
+    entries.sort_by(|a, b| a.key.cmp(&b.key));
+    for entry in entries {
+        let payload = match entry.string {
+            Some(payload) => payload,
+            None => continue,
+        };
+        let mut retry = FIRST_RETRY_SECONDS;
+        while let Err(e) = deliver(callback, &payload).await {
+            warn!(
+                "Error delivering {} to the callback, retrying in {}s: {}",
+                payload, retry, e
+            );
+            time::sleep(Duration::from_secs(retry)).await;
+            retry = (retry * 2).min(MAX_RETRY_SECONDS);
+        }
+        info!("delivered {} to the callback", payload);
+        deldatastore(rpc, entry.key).await?;
+    }
+    Ok(())
+}
+
+/// Hand one event to the callback, fails unless it was accepted.
+pub async fn deliver(callback: &Callback, payload: &str) -> Result<(), Error> {
+    let delivery = async {
+        match callback {
+            Callback::Url(url) => post(url, payload).await,
+            Callback::Exec(path) => exec(path, payload).await,
+        }
+    };
+    time::timeout(Duration::from_secs(CALLBACK_TIMEOUT_SECONDS), delivery)
+        .await
+        .map_err(|_| anyhow!("timed out after {}s", CALLBACK_TIMEOUT_SECONDS))?
+}
+
+async fn post(url: &HttpUrl, payload: &str) -> Result<(), Error> {
+    let mut stream = TcpStream::connect((url.host.as_str(), url.port)).await?;
+    let host = if url.host.contains(':') {
+        format!("[{}]:{}", url.host, url.port)
+    } else {
+        format!("{}:{}", url.host, url.port)
+    };
+    let request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        url.path,
+        host,
+        payload.len(),
+        payload
+    );
+    stream.write_all(request.as_bytes()).await?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await?;
+    let response = String::from_utf8_lossy(&response);
+    let status_line = response.lines().next().unwrap_or_default();
+    match status_line.split_whitespace().nth(1) {
+        Some(status) if status.starts_with('2') => Ok(()),
+        _ => Err(anyhow!("{} answered `{}`", url, status_line)),
+    }
+}
+
+async fn exec(path: &str, payload: &str) -> Result<(), Error> {
+    let mut child = Command::new(path)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| anyhow!("could not run {}: {}", path, e))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        // it is up to the executable to read the event
+        if let Err(e) = stdin.write_all(payload.as_bytes()).await {
+            if e.kind() != std::io::ErrorKind::BrokenPipe {
+                return Err(e.into());
+            }
+        }
+    }
+    let output = child.wait_with_output().await?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "{} exited with {}: {}",
+            path,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(())
+}
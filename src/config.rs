@@ -1,21 +1,238 @@
 use anyhow::{anyhow, Error};
-use cln_plugin::ConfiguredPlugin;
-use log::warn;
+use cln_plugin::{
+    options::{ConfigOption, Value},
+    ConfiguredPlugin,
+};
+use log::info;
 use parking_lot::Mutex;
-use std::{path::Path, sync::Arc};
+use serde::Serialize;
+use std::{collections::HashMap, sync::Arc};
 
-use tokio::fs;
+use tokio::sync::{self, broadcast, watch, Notify};
+
+use crate::{
+    callback::{Callback, HttpUrl},
+    events::{EventLog, Notification, MAX_NOTIFICATIONS},
+    listconfigs,
+    record::OnTimeout,
+    rpc::Rpc,
+    Hodlstate, DEFAULT_HOLD_BLOCKS, DEFAULT_SAFETY_BLOCKS, PLUGIN_NAME,
+};
 
 #[derive(Clone)]
 pub struct PluginState {
     pub config: Arc<Mutex<Config>>,
     pub blockheight: Arc<Mutex<u64>>,
+    pub holds: Arc<Mutex<HashMap<String, Hold>>>,
+    pub events: Arc<Mutex<EventLog>>,
+    pub event_index: Arc<watch::Sender<u64>>,
+    // hands out the event indexes one at a time, held while persisting them
+    pub event_writer: Arc<sync::Mutex<()>>,
+    pub rpc: Arc<dyn Rpc>,
+    pub notifications: broadcast::Sender<Notification>,
+    // wakes up the callback delivery when an event was queued
+    pub outbox: Arc<Notify>,
+}
+
+/// The htlcs currently held for one payment_hash.
+pub struct Hold {
+    pub state: watch::Sender<Option<Hodlstate>>,
+    pub htlcs: Vec<HeldHtlc>,
+    pub invoice_msat: Option<u64>,
+    // stays set once all parts arrived, even after the first parts got released
+    pub complete: bool,
+    // events emitted by the htlc_handler, so that multiple parts don't repeat them
+    pub announced: Vec<Hodlstate>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct HeldHtlc {
+    pub short_channel_id: String,
+    pub id: u64,
+    pub amount_msat: u64,
+    pub cltv_expiry: u64,
+    pub deadline_blockheight: u64,
+    pub held_since: u64,
+    pub hold_until: u64,
+    // only counted towards the payment once it passed the checks
+    #[serde(skip)]
+    pub counted: bool,
 }
+
 impl PluginState {
-    pub fn new() -> PluginState {
+    pub fn new(rpc: Arc<dyn Rpc>) -> PluginState {
         PluginState {
             config: Arc::new(Mutex::new(Config::new())),
             blockheight: Arc::new(Mutex::new(u64::default())),
+            holds: Arc::new(Mutex::new(HashMap::new())),
+            events: Arc::new(Mutex::new(EventLog::new())),
+            event_index: Arc::new(watch::channel(0).0),
+            event_writer: Arc::new(sync::Mutex::new(())),
+            rpc,
+            notifications: broadcast::channel(MAX_NOTIFICATIONS).0,
+            outbox: Arc::new(Notify::new()),
+        }
+    }
+
+    /// Register a held htlc for `pay_hash`, it doesn't count until `count_htlc`.
+    pub fn subscribe_hodlstate(
+        &self,
+        pay_hash: &str,
+        htlc: HeldHtlc,
+    ) -> watch::Receiver<Option<Hodlstate>> {
+        let mut holds = self.holds.lock();
+        let hold = holds.entry(pay_hash.to_string()).or_insert_with(|| Hold {
+            state: watch::channel(None).0,
+            htlcs: Vec::new(),
+            invoice_msat: None,
+            complete: false,
+            announced: Vec::new(),
+        });
+        hold.htlcs.push(htlc);
+        hold.state.subscribe()
+    }
+
+    /// Count a registered htlc towards the payment, once it passed the checks.
+    pub fn count_htlc(&self, pay_hash: &str, short_channel_id: &str, id: u64) {
+        if let Some(hold) = self.holds.lock().get_mut(pay_hash) {
+            for h in hold.htlcs.iter_mut() {
+                if h.short_channel_id == short_channel_id && h.id == id {
+                    h.counted = true;
+                }
+            }
+            // the other parts need to re-check if the payment is complete now
+            hold.state.send_modify(|_| ());
+        }
+    }
+
+    pub fn unsubscribe_hodlstate(&self, pay_hash: &str, short_channel_id: &str, id: u64) {
+        let mut holds = self.holds.lock();
+        if let Some(hold) = holds.get_mut(pay_hash) {
+            hold.htlcs
+                .retain(|h| !(h.short_channel_id == short_channel_id && h.id == id));
+            if hold.htlcs.is_empty() {
+                holds.remove(pay_hash);
+            } else {
+                hold.state.send_modify(|_| ());
+            }
+        }
+    }
+
+    pub fn set_invoice_msat(&self, pay_hash: &str, invoice_msat: u64) {
+        if let Some(hold) = self.holds.lock().get_mut(pay_hash) {
+            hold.invoice_msat = Some(invoice_msat);
+        }
+    }
+
+    pub fn held_msat(&self, pay_hash: &str) -> u64 {
+        match self.holds.lock().get(pay_hash) {
+            Some(hold) => hold
+                .htlcs
+                .iter()
+                .filter(|h| h.counted)
+                .map(|h| h.amount_msat)
+                .sum(),
+            None => 0,
+        }
+    }
+
+    /// True once the held parts add up to the invoice amount.
+    pub fn is_fully_held(&self, pay_hash: &str) -> bool {
+        match self.holds.lock().get_mut(pay_hash) {
+            Some(hold) => {
+                if let Some(invoice_msat) = hold.invoice_msat {
+                    let counted: u64 = hold
+                        .htlcs
+                        .iter()
+                        .filter(|h| h.counted)
+                        .map(|h| h.amount_msat)
+                        .sum();
+                    if counted >= invoice_msat {
+                        hold.complete = true;
+                    }
+                }
+                hold.complete
+            }
+            None => false,
+        }
+    }
+
+    /// True the first time it is called for `state` while htlcs for `pay_hash` are held.
+    pub fn announce(&self, pay_hash: &str, state: Hodlstate) -> bool {
+        match self.holds.lock().get_mut(pay_hash) {
+            Some(hold) if !hold.announced.contains(&state) => {
+                hold.announced.push(state);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// The stored state, or `Held` if we are holding the full amount and wait for a decision.
+    pub fn effective_hodlstate(&self, pay_hash: &str, state: Hodlstate) -> Hodlstate {
+        if state == Hodlstate::Open && self.is_fully_held(pay_hash) {
+            Hodlstate::Held
+        } else {
+            state
+        }
+    }
+
+    /// Fail the htlc `blocks` before its cltv_expiry but not after `latest`, or at the
+    /// unix time `hold_until`, returns the resulting block height.
+    pub fn set_htlc_deadline(
+        &self,
+        pay_hash: &str,
+        short_channel_id: &str,
+        id: u64,
+        blocks: u64,
+        latest: u64,
+        hold_until: u64,
+    ) -> u64 {
+        let mut deadline = 0;
+        if let Some(hold) = self.holds.lock().get_mut(pay_hash) {
+            for h in hold.htlcs.iter_mut() {
+                if h.short_channel_id == short_channel_id && h.id == id {
+                    h.deadline_blockheight = h.cltv_expiry.saturating_sub(blocks).min(latest);
+                    h.hold_until = hold_until;
+                    deadline = h.deadline_blockheight;
+                }
+            }
+        }
+        deadline
+    }
+
+    pub fn held_htlcs(&self, pay_hash: &str) -> Vec<HeldHtlc> {
+        match self.holds.lock().get(pay_hash) {
+            Some(hold) => hold.htlcs.iter().filter(|h| h.counted).cloned().collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Set the state read from the datastore, unless an rpc call already updated it.
+    pub fn init_hodlstate(&self, pay_hash: &str, state: Hodlstate) {
+        if let Some(hold) = self.holds.lock().get(pay_hash) {
+            hold.state.send_if_modified(|s| {
+                if s.is_none() {
+                    *s = Some(state);
+                    true
+                } else {
+                    false
+                }
+            });
+        }
+    }
+
+    /// Wake up the htlcs held for `pay_hash`, call after persisting `state` in the datastore.
+    pub fn update_hodlstate(&self, pay_hash: &str, state: Hodlstate) {
+        if let Some(hold) = self.holds.lock().get(pay_hash) {
+            hold.state.send_replace(Some(state));
+        }
+    }
+
+    /// Wake up all held htlcs to re-check their deadlines.
+    pub fn wake_all(&self) {
+        for hold in self.holds.lock().values() {
+            hold.state.send_modify(|_| ());
         }
     }
 }
@@ -23,11 +240,96 @@ impl PluginState {
 #[derive(Clone, Debug)]
 pub struct Config {
     pub cltv_delta: (String, u16),
+    pub safety_blocks: (String, u32),
+    pub hold_blocks: (String, u32),
+    pub max_hold_seconds: (String, u64),
+    pub on_timeout: (String, OnTimeout),
+    pub retention_seconds: (String, u64),
+    pub overpayment_percent: (String, u64),
+    pub callback_url: (String, Option<HttpUrl>),
+    pub callback_exec: (String, Option<String>),
+    pub network: String,
 }
 impl Config {
     pub fn new() -> Config {
         Config {
-            cltv_delta: ("cltv-delta".to_string(), 40),
+            cltv_delta: (PLUGIN_NAME.to_string() + "-cltv-delta", 40),
+            safety_blocks: (
+                PLUGIN_NAME.to_string() + "-safety-blocks",
+                DEFAULT_SAFETY_BLOCKS,
+            ),
+            hold_blocks: (
+                PLUGIN_NAME.to_string() + "-hold-blocks",
+                DEFAULT_HOLD_BLOCKS,
+            ),
+            max_hold_seconds: (PLUGIN_NAME.to_string() + "-max-hold-seconds", 86_400),
+            on_timeout: (PLUGIN_NAME.to_string() + "-on-timeout", OnTimeout::Reject),
+            retention_seconds: (PLUGIN_NAME.to_string() + "-retention-seconds", 2_592_000),
+            // lightningd accepts up to twice the invoice amount
+            overpayment_percent: (PLUGIN_NAME.to_string() + "-overpayment-percent", 100),
+            callback_url: (PLUGIN_NAME.to_string() + "-callback-url", None),
+            callback_exec: (PLUGIN_NAME.to_string() + "-callback-exec", None),
+            network: "bitcoin".to_string(),
+        }
+    }
+
+    pub fn options() -> Vec<ConfigOption> {
+        let config = Config::new();
+        vec![
+            ConfigOption::new(
+                &config.cltv_delta.0,
+                Value::OptInteger,
+                "cltv-delta used for hold-invoices, defaults to the node's cltv-delta",
+            ),
+            ConfigOption::new(
+                &config.safety_blocks.0,
+                Value::Integer(config.safety_blocks.1 as i64),
+                "fail held htlcs this many blocks (plus cltv-delta) before they expire",
+            ),
+            ConfigOption::new(
+                &config.hold_blocks.0,
+                Value::Integer(config.hold_blocks.1 as i64),
+                "default number of blocks htlcs can be held for, overridden by `hold_blocks`",
+            ),
+            ConfigOption::new(
+                &config.max_hold_seconds.0,
+                Value::Integer(config.max_hold_seconds.1 as i64),
+                "default number of seconds htlcs can be held for, overridden by `max_hold_seconds`",
+            ),
+            ConfigOption::new(
+                &config.on_timeout.0,
+                Value::String("reject".to_string()),
+                "`reject` or `accept` held htlcs if no decision was made before they time out",
+            ),
+            ConfigOption::new(
+                &config.retention_seconds.0,
+                Value::Integer(config.retention_seconds.1 as i64),
+                "delete settled, canceled and expired hold-invoices after this many seconds",
+            ),
+            ConfigOption::new(
+                &config.overpayment_percent.0,
+                Value::Integer(config.overpayment_percent.1 as i64),
+                "fail htlcs paying more than this many percent above the invoice amount",
+            ),
+            ConfigOption::new(
+                &config.callback_url.0,
+                Value::OptString,
+                "http:// URL the hold-invoice events are POSTed to",
+            ),
+            ConfigOption::new(
+                &config.callback_exec.0,
+                Value::OptString,
+                "executable that is run with each hold-invoice event on stdin",
+            ),
+        ]
+    }
+
+    /// Where to deliver the hold-invoice events, if anywhere.
+    pub fn callback(&self) -> Option<Callback> {
+        match (&self.callback_url.1, &self.callback_exec.1) {
+            (Some(url), _) => Some(Callback::Url(url.clone())),
+            (None, Some(path)) => Some(Callback::Exec(path.clone())),
+            (None, None) => None,
         }
     }
 }
@@ -36,42 +338,101 @@ pub async fn read_config(
     plugin: &ConfiguredPlugin<PluginState, tokio::io::Stdin, tokio::io::Stdout>,
     state: PluginState,
 ) -> Result<(), Error> {
-    let mut configfile = String::new();
-    let dir = plugin.clone().configuration().lightning_dir;
-    match fs::read_to_string(Path::new(&dir).join("config")).await {
-        Ok(file) => configfile = file,
-        Err(_) => {
-            match fs::read_to_string(Path::new(&dir).parent().unwrap().join("config")).await {
-                Ok(file2) => configfile = file2,
-                Err(_) => warn!("No config file found!"),
-            }
+    let mut config = state.config.lock().clone();
+    config.network = plugin.configuration().network;
+
+    config.cltv_delta.1 = match plugin.option(&config.cltv_delta.0).and_then(|v| v.as_i64()) {
+        Some(n) => u16::try_from(n).map_err(|e| {
+            anyhow!(
+                "Error: `{}` is not a valid value for {}: {}",
+                n,
+                config.cltv_delta.0,
+                e
+            )
+        })?,
+        None => {
+            let cltv_delta = listconfigs(state.rpc.as_ref(), Some("cltv-delta".to_string()))
+                .await?
+                .cltv_delta
+                .ok_or(anyhow!("Error: could not read cltv-delta from listconfigs"))?;
+            u16::try_from(cltv_delta)
+                .map_err(|e| anyhow!("Error: invalid cltv-delta `{}`: {}", cltv_delta, e))?
         }
-    }
-    let mut config = state.config.lock();
-    for line in configfile.lines() {
-        if line.contains('=') {
-            let splitline = line.split('=').collect::<Vec<&str>>();
-            if splitline.len() == 2 {
-                let name = splitline.clone().into_iter().nth(0).unwrap();
-                let value = splitline.into_iter().nth(1).unwrap();
-
-                match name {
-                    opt if opt.eq(&config.cltv_delta.0) => match value.parse::<u16>() {
-                        Ok(n) => config.cltv_delta.1 = n,
-                        Err(e) => {
-                            return Err(anyhow!(
-                                "Error: Could not parse a number from `{}` for {}: {}",
-                                value,
-                                config.cltv_delta.0,
-                                e
-                            ))
-                        }
-                    },
-                    _ => (),
-                }
+    };
+    config.safety_blocks.1 = u32::try_from(positive_option(plugin, &config.safety_blocks)?)
+        .map_err(|e| anyhow!("Error: {} is too big: {}", config.safety_blocks.0, e))?;
+    config.hold_blocks.1 = u32::try_from(positive_option(plugin, &config.hold_blocks)?)
+        .map_err(|e| anyhow!("Error: {} is too big: {}", config.hold_blocks.0, e))?;
+    config.max_hold_seconds.1 = positive_option(plugin, &config.max_hold_seconds)?;
+    config.retention_seconds.1 = positive_option(plugin, &config.retention_seconds)?;
+    if let Some(value) = plugin.option(&config.overpayment_percent.0) {
+        config.overpayment_percent.1 = match value.as_i64() {
+            Some(n) if n >= 0 => n as u64,
+            _ => {
+                return Err(anyhow!(
+                    "Error: {} must be zero or a positive integer, got {:?}",
+                    config.overpayment_percent.0,
+                    value
+                ))
             }
-        }
+        };
+    }
+    if let Some(value) = plugin.option(&config.on_timeout.0) {
+        config.on_timeout.1 = value.as_str().and_then(OnTimeout::from_str).ok_or(anyhow!(
+            "Error: {} must be `reject` or `accept`, got {:?}",
+            config.on_timeout.0,
+            value
+        ))?;
+    }
+    if let Some(value) = plugin.option(&config.callback_url.0) {
+        let url = value.as_str().ok_or(anyhow!(
+            "Error: {} must be a string, got {:?}",
+            config.callback_url.0,
+            value
+        ))?;
+        config.callback_url.1 = Some(
+            HttpUrl::parse(url)
+                .map_err(|e| anyhow!("Error: invalid {}: {}", config.callback_url.0, e))?,
+        );
+    }
+    if let Some(value) = plugin.option(&config.callback_exec.0) {
+        config.callback_exec.1 = Some(
+            value
+                .as_str()
+                .ok_or(anyhow!(
+                    "Error: {} must be a string, got {:?}",
+                    config.callback_exec.0,
+                    value
+                ))?
+                .to_string(),
+        );
+    }
+    if config.callback_url.1.is_some() && config.callback_exec.1.is_some() {
+        return Err(anyhow!(
+            "Error: set either {} or {}, not both",
+            config.callback_url.0,
+            config.callback_exec.0
+        ));
     }
 
+    info!("{:?}", config);
+    *state.config.lock() = config;
     Ok(())
 }
+
+fn positive_option<T: Into<u64> + Copy>(
+    plugin: &ConfiguredPlugin<PluginState, tokio::io::Stdin, tokio::io::Stdout>,
+    option: &(String, T),
+) -> Result<u64, Error> {
+    match plugin.option(&option.0) {
+        Some(value) => match value.as_i64() {
+            Some(n) if n > 0 => Ok(n as u64),
+            _ => Err(anyhow!(
+                "Error: {} must be a positive integer, got {:?}",
+                option.0,
+                value
+            )),
+        },
+        None => Ok(option.1.into()),
+    }
+}
@@ -0,0 +1,218 @@
+use std::fmt;
+
+use anyhow::Error;
+use cln_rpc::RpcError;
+use serde_json::json;
+
+use crate::Hodlstate;
+
+/// JSON-RPC error code for invalid method parameters, the same lightningd uses.
+pub const INVALID_PARAMS: i32 = -32602;
+/// JSON-RPC error code for everything we have no better code for.
+pub const INTERNAL_ERROR: i32 = -32603;
+pub const UNKNOWN_HODLVOICE: i32 = 2101;
+pub const ILLEGAL_TRANSITION: i32 = 2102;
+pub const INVOICE_EXPIRED: i32 = 2103;
+pub const LIGHTNINGD_ERROR: i32 = 2104;
+pub const TIMED_OUT: i32 = 2105;
+pub const INVOICE_PAID: i32 = 2106;
+pub const CONCURRENT_UPDATE: i32 = 2107;
+pub const EVENTS_MISSING: i32 = 2108;
+
+/// Why a `hodlvoice-*` rpc method failed, each kind with a stable error code.
+#[derive(Debug, Clone)]
+pub enum HodlError {
+    /// No hold-invoice for this payment hash or label.
+    UnknownHodlvoice(String),
+    /// The hold-invoice is already decided.
+    IllegalTransition {
+        payment_hash: String,
+        current: Hodlstate,
+        requested: Hodlstate,
+    },
+    /// The hold-invoice expired before the decision.
+    InvoiceExpired {
+        payment_hash: String,
+        requested: Hodlstate,
+    },
+    InvalidParameter(String),
+    /// A call to lightningd failed, `code` is lightningd's error code if it sent one.
+    Lightningd {
+        method: String,
+        code: Option<i32>,
+        message: String,
+    },
+    /// No event arrived within the `timeout` of a wait, in seconds.
+    TimedOut(u64),
+    /// The invoice was paid, it can't be deleted anymore.
+    InvoicePaid(String),
+    /// The record kept changing while we tried to update it.
+    ConcurrentUpdate {
+        payment_hash: String,
+        attempts: usize,
+    },
+    /// The events after `lastindex` are not kept anymore.
+    EventsMissing {
+        lastindex: u64,
+        last_index: u64,
+    },
+}
+impl fmt::Display for HodlError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HodlError::UnknownHodlvoice(id) => write!(f, "hold-invoice not found: {}", id),
+            HodlError::IllegalTransition {
+                payment_hash,
+                current,
+                requested,
+            } => write!(
+                f,
+                "hold-invoice {} is already {}, can not change it to {}",
+                payment_hash,
+                current.to_string(),
+                requested.to_string()
+            ),
+            HodlError::InvoiceExpired {
+                payment_hash,
+                requested,
+            } => write!(
+                f,
+                "hold-invoice {} is expired, can not change it to {}",
+                payment_hash,
+                requested.to_string()
+            ),
+            HodlError::InvalidParameter(message) => write!(f, "{}", message),
+            HodlError::Lightningd {
+                method, message, ..
+            } => write!(f, "Error calling {}: {}", method, message),
+            HodlError::TimedOut(seconds) => write!(f, "Timed out after {}s", seconds),
+            HodlError::InvoicePaid(payment_hash) => {
+                write!(f, "invoice for {} was paid, not deleting it", payment_hash)
+            }
+            HodlError::ConcurrentUpdate {
+                payment_hash,
+                attempts,
+            } => write!(
+                f,
+                "hold-invoice {} kept changing, gave up after {} attempts",
+                payment_hash, attempts
+            ),
+            HodlError::EventsMissing { lastindex, .. } => {
+                write!(f, "events after index {} are not kept anymore", lastindex)
+            }
+        }
+    }
+}
+impl std::error::Error for HodlError {}
+
+impl HodlError {
+    pub fn code(&self) -> i32 {
+        match self {
+            HodlError::UnknownHodlvoice(_) => UNKNOWN_HODLVOICE,
+            HodlError::IllegalTransition { .. } => ILLEGAL_TRANSITION,
+            HodlError::InvoiceExpired { .. } => INVOICE_EXPIRED,
+            HodlError::InvalidParameter(_) => INVALID_PARAMS,
+            HodlError::Lightningd { .. } => LIGHTNINGD_ERROR,
+            HodlError::TimedOut(_) => TIMED_OUT,
+            HodlError::InvoicePaid(_) => INVOICE_PAID,
+            HodlError::ConcurrentUpdate { .. } => CONCURRENT_UPDATE,
+            HodlError::EventsMissing { .. } => EVENTS_MISSING,
+        }
+    }
+
+    /// The state that kept a transition from happening, if it was refused.
+    pub fn refused_by(&self) -> Option<Hodlstate> {
+        match self {
+            HodlError::IllegalTransition { current, .. } => Some(current.clone()),
+            HodlError::InvoiceExpired { .. } => Some(Hodlstate::Expired),
+            _ => None,
+        }
+    }
+
+    fn data(&self) -> Option<serde_json::Value> {
+        match self {
+            HodlError::IllegalTransition {
+                payment_hash,
+                current,
+                requested,
+            } => Some(json!({
+                "payment_hash": payment_hash,
+                "state": current.to_string(),
+                "requested": requested.to_string(),
+            })),
+            HodlError::InvoiceExpired { payment_hash, .. } => Some(json!({
+                "payment_hash": payment_hash,
+                "state": Hodlstate::Expired.to_string(),
+            })),
+            HodlError::Lightningd { method, code, .. } => Some(json!({
+                "method": method,
+                "code": code,
+            })),
+            HodlError::InvoicePaid(payment_hash)
+            | HodlError::ConcurrentUpdate { payment_hash, .. } => Some(json!({
+                "payment_hash": payment_hash,
+            })),
+            HodlError::EventsMissing { last_index, .. } => Some(json!({
+                "last_index": last_index,
+            })),
+            _ => None,
+        }
+    }
+}
+
+/// The state that kept a transition from happening, if `e` is such a refusal.
+pub fn refused_by(e: &Error) -> Option<Hodlstate> {
+    e.downcast_ref::<HodlError>()
+        .and_then(HodlError::refused_by)
+}
+
+/// A failed call to lightningd `method`.
+pub fn lightningd_error(method: &str, e: RpcError) -> Error {
+    HodlError::Lightningd {
+        method: method.to_string(),
+        code: e.code,
+        message: e.message,
+    }
+    .into()
+}
+
+/// lightningd answered `method` with a response for another method.
+pub fn unexpected_response(method: &str, response: impl fmt::Debug) -> Error {
+    HodlError::Lightningd {
+        method: method.to_string(),
+        code: None,
+        message: format!("unexpected result: {:?}", response),
+    }
+    .into()
+}
+
+/// The code the caller gets for `e`.
+pub fn error_code(e: &Error) -> i32 {
+    if let Some(h) = e.downcast_ref::<HodlError>() {
+        h.code()
+    } else if let Some(r) = e.downcast_ref::<RpcError>() {
+        r.code.unwrap_or(INTERNAL_ERROR)
+    } else {
+        INTERNAL_ERROR
+    }
+}
+
+/// The error cln-plugin sends back to the caller of an rpc method, with our error code.
+pub fn rpc_error(e: Error) -> Error {
+    let error = match e.downcast::<HodlError>() {
+        Ok(h) => RpcError {
+            code: Some(h.code()),
+            message: h.to_string(),
+            data: h.data(),
+        },
+        Err(e) => match e.downcast::<RpcError>() {
+            Ok(r) => r,
+            Err(e) => RpcError {
+                code: Some(INTERNAL_ERROR),
+                message: e.to_string(),
+                data: None,
+            },
+        },
+    };
+    error.into()
+}
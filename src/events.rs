@@ -0,0 +1,277 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Error};
+use cln_plugin::Plugin;
+use cln_rpc::model::DatastoreMode;
+use log::warn;
+use serde::Serialize;
+use serde_json::json;
+use tokio::{
+    sync::broadcast,
+    time::{self, Instant},
+};
+
+use crate::{
+    callback::queue_callback,
+    config::PluginState,
+    datastore,
+    error::{rpc_error, HodlError},
+    listdatastore,
+    params::{Params, WaitRequest, WaitanyRequest},
+    record::HodlRecord,
+    Hodlstate, PLUGIN_NAME,
+};
+
+// how many past events we keep around for `hodlvoice-waitany`
+const MAX_EVENTS: usize = 1000;
+// how many notifications can be queued before they are sent to lightningd
+pub const MAX_NOTIFICATIONS: usize = 1000;
+
+pub const NOTIFICATION_HTLC_HELD: &str = "hodlvoice_htlc_held";
+pub const NOTIFICATION_INCONSISTENCY: &str = "hodlvoice_inconsistency";
+pub const NOTIFICATION_TOPICS: [&str; 7] = [
+    NOTIFICATION_HTLC_HELD,
+    NOTIFICATION_INCONSISTENCY,
+    "hodlvoice_held",
+    "hodlvoice_accepted",
+    "hodlvoice_settled",
+    "hodlvoice_canceled",
+    "hodlvoice_expired",
+];
+
+fn notification_topic(hodlstate: &Hodlstate) -> Option<&'static str> {
+    match hodlstate {
+        Hodlstate::Open => None,
+        Hodlstate::Held => Some(NOTIFICATION_TOPICS[2]),
+        Hodlstate::Accepted => Some(NOTIFICATION_TOPICS[3]),
+        Hodlstate::Settled => Some(NOTIFICATION_TOPICS[4]),
+        Hodlstate::Canceled => Some(NOTIFICATION_TOPICS[5]),
+        Hodlstate::Expired => Some(NOTIFICATION_TOPICS[6]),
+    }
+}
+
+/// A custom notification waiting to be sent to lightningd.
+#[derive(Clone, Debug)]
+pub struct Notification {
+    pub topic: String,
+    pub payload: serde_json::Value,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct HodlEvent {
+    pub index: u64,
+    pub payment_hash: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    pub state: String,
+    pub timestamp: u64,
+}
+
+pub struct EventLog {
+    pub last_index: u64,
+    pub events: VecDeque<HodlEvent>,
+}
+impl EventLog {
+    pub fn new() -> EventLog {
+        EventLog {
+            last_index: 0,
+            events: VecDeque::new(),
+        }
+    }
+
+    fn next_after(&self, index: u64, pay_hash: Option<&str>) -> Option<HodlEvent> {
+        self.events
+            .iter()
+            .find(|e| e.index > index && pay_hash.map_or(true, |ph| ph == e.payment_hash))
+            .cloned()
+    }
+
+    /// Whether events after `index` were dropped or happened before the last restart.
+    fn missing_after(&self, index: u64) -> bool {
+        let oldest = self.events.front().map_or(self.last_index + 1, |e| e.index);
+        index + 1 < oldest
+    }
+}
+
+/// Record the state transition of `record`, wake up everyone waiting for it and
+/// queue it for the callback.
+pub async fn emit_event(state: &PluginState, record: &HodlRecord) -> Result<(), Error> {
+    // the index is stored before it is handed out, so the stored index never goes
+    // back and no index is used twice, not even after a restart
+    let _writer = state.event_writer.lock().await;
+    let index = state.events.lock().last_index + 1;
+    let persisted = datastore(
+        state.rpc.as_ref(),
+        vec![PLUGIN_NAME.to_string(), "eventindex".to_string()],
+        Some(index.to_string()),
+        None,
+        Some(DatastoreMode::CREATE_OR_REPLACE),
+        None,
+    )
+    .await;
+    let event = {
+        let mut log = state.events.lock();
+        log.last_index = index;
+        let event = HodlEvent {
+            index,
+            payment_hash: record.payment_hash.clone(),
+            label: record.label.clone(),
+            state: record.state.to_string(),
+            timestamp: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+        };
+        log.events.push_back(event.clone());
+        if log.events.len() > MAX_EVENTS {
+            log.events.pop_front();
+        }
+        event
+    };
+    state.event_index.send_replace(event.index);
+    if let Some(topic) = notification_topic(&record.state) {
+        notify(state, topic, json!(event));
+    }
+    // queued in index order, the callback gets them in the same order
+    let queued = queue_callback(state, &event).await;
+    persisted?;
+    queued
+}
+
+/// Queue a custom notification, other plugins subscribed to `topic` will receive `payload`.
+pub fn notify(state: &PluginState, topic: &str, payload: serde_json::Value) {
+    // nobody is listening before the plugin started
+    let _ = state.notifications.send(Notification {
+        topic: topic.to_string(),
+        payload,
+    });
+}
+
+/// Send the queued notifications to lightningd.
+pub fn forward_notifications(plugin: &Plugin<PluginState>) {
+    let mut rx = plugin.state().notifications.subscribe();
+    let plugin = plugin.clone();
+    tokio::spawn(async move {
+        loop {
+            match rx.recv().await {
+                Ok(n) => {
+                    if let Err(e) = plugin
+                        .send_custom_notification(n.topic.clone(), n.payload)
+                        .await
+                    {
+                        warn!("Error sending {} notification: {}", n.topic, e);
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    warn!("dropped {} notifications", missed)
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    });
+}
+
+/// Continue counting where we stopped before the last restart.
+pub async fn load_event_index(state: &PluginState) -> Result<(), Error> {
+    let resp = listdatastore(
+        state.rpc.as_ref(),
+        Some(vec![PLUGIN_NAME.to_string(), "eventindex".to_string()]),
+    )
+    .await?;
+    if let Some(index) = resp.datastore.first().and_then(|d| d.string.as_ref()) {
+        let index = index
+            .parse::<u64>()
+            .map_err(|e| anyhow!("invalid eventindex `{}`: {}", index, e))?;
+        state.events.lock().last_index = index;
+        state.event_index.send_replace(index);
+    }
+    Ok(())
+}
+
+pub async fn hodlvoicewait(
+    plugin: Plugin<PluginState>,
+    args: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    wait_hodlvoice(plugin.state(), args)
+        .await
+        .map_err(rpc_error)
+}
+
+/// The `hodlvoice-wait` rpc method without the plugin.
+pub async fn wait_hodlvoice(
+    state: &PluginState,
+    args: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    let req = WaitRequest::parse(args)?;
+    let pay_hash = req.payment_hash.to_lowercase();
+
+    if listdatastore(
+        state.rpc.as_ref(),
+        Some(vec![PLUGIN_NAME.to_string(), pay_hash.clone()]),
+    )
+    .await?
+    .datastore
+    .is_empty()
+    {
+        return Err(HodlError::UnknownHodlvoice(pay_hash).into());
+    }
+
+    let after = state.events.lock().last_index;
+    wait_for_event(state, after, Some(&pay_hash), req.timeout).await
+}
+
+pub async fn hodlvoicewaitany(
+    plugin: Plugin<PluginState>,
+    args: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    waitany_hodlvoice(plugin.state(), args)
+        .await
+        .map_err(rpc_error)
+}
+
+/// The `hodlvoice-waitany` rpc method without the plugin.
+pub async fn waitany_hodlvoice(
+    state: &PluginState,
+    args: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    let req = WaitanyRequest::parse(args)?;
+    let lastindex = match req.lastindex {
+        Some(li) => {
+            let log = state.events.lock();
+            if log.missing_after(li) {
+                return Err(HodlError::EventsMissing {
+                    lastindex: li,
+                    last_index: log.last_index,
+                }
+                .into());
+            }
+            li
+        }
+        None => state.events.lock().last_index,
+    };
+
+    wait_for_event(state, lastindex, None, req.timeout).await
+}
+
+async fn wait_for_event(
+    state: &PluginState,
+    after: u64,
+    pay_hash: Option<&str>,
+    timeout: Option<u64>,
+) -> Result<serde_json::Value, Error> {
+    // subscribe before looking so we can't miss an event emitted in between
+    let mut rx = state.event_index.subscribe();
+    let deadline = timeout.map(|t| Instant::now() + Duration::from_secs(t));
+    loop {
+        if let Some(event) = state.events.lock().next_after(after, pay_hash) {
+            return Ok(json!(event));
+        }
+        let changed = match deadline {
+            Some(d) => time::timeout_at(d, rx.changed())
+                .await
+                .map_err(|_| HodlError::TimedOut(timeout.unwrap_or_default()))?,
+            None => rx.changed().await,
+        };
+        changed.map_err(|_| anyhow!("event channel closed"))?;
+    }
+}
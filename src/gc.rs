@@ -0,0 +1,122 @@
+use std::{
+    collections::HashSet,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Error;
+use cln_plugin::Plugin;
+use cln_rpc::model::ListinvoicesInvoicesStatus;
+use log::{info, warn};
+use serde::Serialize;
+use serde_json::json;
+
+use crate::{
+    config::PluginState,
+    deldatastore,
+    error::rpc_error,
+    listinvoices,
+    params::{GcRequest, Params},
+    record::list_records,
+    PLUGIN_NAME,
+};
+
+// run the automatic cleanup about once a day
+pub const GC_INTERVAL_BLOCKS: u64 = 144;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Collected {
+    pub payment_hash: String,
+    pub state: String,
+    pub label: Option<String>,
+    pub finished_at: u64,
+}
+
+pub async fn hodlvoicegc(
+    plugin: Plugin<PluginState>,
+    args: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    gc_hodlvoices(plugin.state(), args).await.map_err(rpc_error)
+}
+
+/// The `hodlvoice-gc` rpc method without the plugin.
+pub async fn gc_hodlvoices(
+    state: &PluginState,
+    args: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    let dry_run = GcRequest::parse(args)?.dry_run.unwrap_or(false);
+
+    let collected = collect_garbage(state, dry_run).await?;
+    Ok(json!({
+        "dry_run": dry_run,
+        "deleted": collected,
+    }))
+}
+
+/// Start a cleanup in the background, called for every new block.
+pub fn schedule_gc(state: &PluginState, blockheight: u64) {
+    if blockheight % GC_INTERVAL_BLOCKS != 0 {
+        return;
+    }
+    let state = state.clone();
+    tokio::spawn(async move {
+        match collect_garbage(&state, false).await {
+            Ok(collected) => info!(
+                "deleted {} finished hold-invoices from the datastore",
+                collected.len()
+            ),
+            Err(e) => warn!("Error cleaning up hold-invoices: {}", e),
+        }
+    });
+}
+
+/// Delete the records of hold-invoices that finished longer than the retention period ago.
+pub async fn collect_garbage(state: &PluginState, dry_run: bool) -> Result<Vec<Collected>, Error> {
+    let rpc = state.rpc.as_ref();
+    let retention = state.config.lock().retention_seconds.1;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+
+    // without its record a payment of a rejected invoice would be settled by lightningd
+    let unpaid: HashSet<String> = listinvoices(rpc, None, None)
+        .await?
+        .invoices
+        .iter()
+        .filter(|inv| inv.status == ListinvoicesInvoicesStatus::UNPAID)
+        .map(|inv| inv.payment_hash.to_string())
+        .collect();
+
+    let mut collected = Vec::new();
+    for record in list_records(rpc).await? {
+        if !record.state.is_final() {
+            continue;
+        }
+        if state.holds.lock().contains_key(&record.payment_hash) {
+            continue;
+        }
+        if unpaid.contains(&record.payment_hash) {
+            continue;
+        }
+        let finished_at = record
+            .history
+            .last()
+            .map(|t| t.at)
+            .unwrap_or(record.created_at);
+        if finished_at + retention > now {
+            continue;
+        }
+
+        if !dry_run {
+            deldatastore(
+                rpc,
+                vec![PLUGIN_NAME.to_string(), record.payment_hash.clone()],
+            )
+            .await?;
+        }
+        collected.push(Collected {
+            payment_hash: record.payment_hash,
+            state: record.state.to_string(),
+            label: record.label,
+            finished_at,
+        });
+    }
+    Ok(collected)
+}
@@ -1,129 +1,541 @@
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use anyhow::{anyhow, Error};
+use bitcoin::hashes::{sha256, Hash};
 use cln_plugin::Plugin;
-use log::{debug, warn};
+use log::{debug, info, warn};
 use serde_json::json;
-use tokio::time;
+use tokio::{sync::watch, time};
 
 use crate::{
-    config::PluginState, listdatastore, listinvoices, make_rpc_path, Hodlstate, CLTV_HODL,
-    PLUGIN_NAME,
+    config::{HeldHtlc, PluginState},
+    error::refused_by,
+    events::{notify, NOTIFICATION_HTLC_HELD},
+    gc::schedule_gc,
+    getinfo, listinvoices, msat_from_value,
+    record::{expire_records, get_record, record_payment, transition_record, OnTimeout, Policy},
+    Hodlstate,
 };
 
 pub async fn htlc_handler(
     plugin: Plugin<PluginState>,
     v: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    handle_htlc(plugin.state(), v).await
+}
+
+/// Decide what to do with an htlc passed to the `htlc_accepted` hook.
+pub async fn handle_htlc(
+    state: &PluginState,
+    v: serde_json::Value,
 ) -> Result<serde_json::Value, Error> {
     if let Some(htlc) = v.get("htlc") {
         if let Some(pay_hash) = htlc
             .get("payment_hash")
             .and_then(|pay_hash| pay_hash.as_str())
         {
-            let rpc_path = make_rpc_path(&plugin);
-            let mut invoice = None;
-            let cltv_delta = plugin.state().config.lock().clone().cltv_delta.1 as u64;
             let cltv_expiry = match htlc.get("cltv_expiry") {
-                Some(ce) => ce.as_u64().unwrap(),
+                Some(ce) => ce.as_u64().ok_or(anyhow!(
+                    "invalid cltv_expiry {}! payment_hash: {}",
+                    ce,
+                    pay_hash
+                ))?,
                 None => return Err(anyhow!("expiry not found! payment_hash: {}", pay_hash)),
             };
-            loop {
-                {
-                    match listdatastore(
-                        &rpc_path,
-                        Some(vec![PLUGIN_NAME.to_string(), pay_hash.to_string()]),
-                    )
-                    .await
-                    {
-                        Ok(resp) => {
-                            if resp.datastore.len() != 1 {
-                                return Err(anyhow!(
-                                    "wrong amount of results found for payment_hash: {} {:?}",
-                                    pay_hash,
-                                    resp.datastore
-                                ));
+
+            let held_htlc = HeldHtlc {
+                short_channel_id: htlc
+                    .get("short_channel_id")
+                    .and_then(|scid| scid.as_str())
+                    .unwrap_or_default()
+                    .to_string(),
+                id: htlc
+                    .get("id")
+                    .and_then(|id| id.as_u64())
+                    .unwrap_or_default(),
+                amount_msat: htlc
+                    .get("amount_msat")
+                    .and_then(msat_from_value)
+                    .unwrap_or_default(),
+                cltv_expiry,
+                // set once we know the policy of the invoice
+                deadline_blockheight: 0,
+                held_since: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs(),
+                hold_until: 0,
+                counted: false,
+            };
+            let (short_channel_id, id) = (held_htlc.short_channel_id.clone(), held_htlc.id);
+
+            // register before reading the datastore so we can't miss a state change
+            // that happens in between
+            let mut rx = state.subscribe_hodlstate(pay_hash, held_htlc);
+            let result = hold_htlc(state, &v, pay_hash, &mut rx, &short_channel_id, id).await;
+            drop(rx);
+            state.unsubscribe_hodlstate(pay_hash, &short_channel_id, id);
+            return result;
+        }
+    }
+    Ok(json!({"result": "continue"}))
+}
+
+async fn hold_htlc(
+    state: &PluginState,
+    v: &serde_json::Value,
+    pay_hash: &str,
+    rx: &mut watch::Receiver<Option<Hodlstate>>,
+    short_channel_id: &str,
+    id: u64,
+) -> Result<serde_json::Value, Error> {
+    let rpc = state.rpc.as_ref();
+    let record = match get_record(rpc, pay_hash).await {
+        Ok(Some(record)) => record,
+        Ok(None) => {
+            debug!("not our invoice: payment_hash: {}", pay_hash);
+            return Ok(json!({"result": "continue"}));
+        }
+        Err(e) => {
+            warn!(
+                "{} could not read hodlvoice record for payment_hash: {}, rejecting!",
+                e.to_string(),
+                pay_hash
+            );
+            return Ok(json!({"result": "fail"}));
+        }
+    };
+    let stored_state = record.state.clone();
+    // the invoice may be deleted already
+    if matches!(stored_state, Hodlstate::Canceled | Hodlstate::Expired) {
+        debug!(
+            "{} invoice with payment_hash: {}, rejecting!",
+            stored_state.to_string(),
+            pay_hash
+        );
+        return Ok(json!({"result": "fail"}));
+    }
+
+    // without a block height we can't tell when the htlc has to be failed
+    let blockheight = *state.blockheight.lock();
+    if blockheight == 0 {
+        warn!(
+            "block height not known yet, not holding htlc for payment_hash: {}",
+            pay_hash
+        );
+        return Ok(json!({"result": "fail"}));
+    }
+
+    let config = state.config.lock().clone();
+    // a payer adding more cltv than the invoice asked for doesn't make us hold it longer,
+    // migrated records have no hold window and are held as long as the cltv allows
+    let latest = match record.policy.hold_blocks {
+        0 => u64::MAX,
+        hold_blocks => blockheight + hold_blocks as u64,
+    };
+    let max_hold_seconds = record
+        .policy
+        .max_hold_seconds
+        .unwrap_or(config.max_hold_seconds.1);
+    let hold_until = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() + max_hold_seconds;
+    let deadline_blockheight = state.set_htlc_deadline(
+        pay_hash,
+        short_channel_id,
+        id,
+        config.cltv_delta.1 as u64 + record.policy.safety_blocks as u64,
+        latest,
+        hold_until,
+    );
+    state.init_hodlstate(pay_hash, stored_state);
+
+    // the total the payer announced, a single part may leave it out
+    let total_msat = v
+        .get("onion")
+        .and_then(|o| o.get("total_msat"))
+        .and_then(msat_from_value)
+        .or_else(|| {
+            v.get("htlc")
+                .and_then(|h| h.get("amount_msat"))
+                .and_then(msat_from_value)
+        })
+        .unwrap_or_default();
+    let (expires_at, fixed_msat) = match listinvoices(rpc, None, Some(pay_hash.to_string()))
+        .await?
+        .invoices
+        .first()
+    {
+        Some(inv) => (inv.expires_at, inv.amount_msat.map(|a| a.msat())),
+        None => {
+            let external = record
+                .external
+                .as_ref()
+                .ok_or(anyhow!("invoice not found"))?;
+            let payment_secret = v
+                .get("onion")
+                .and_then(|o| o.get("payment_secret"))
+                .and_then(|s| s.as_str());
+            if payment_secret != Some(external.payment_secret.as_str()) {
+                warn!(
+                    "wrong payment_secret for payment_hash: {}, rejecting!",
+                    pay_hash
+                );
+                return Ok(json!({"result": "fail"}));
+            }
+            (external.expires_at, external.amount_msat)
+        }
+    };
+    // not counted yet, so a part that fails the checks never completes the payment
+    let amount_msat = v
+        .get("htlc")
+        .and_then(|h| h.get("amount_msat"))
+        .and_then(msat_from_value)
+        .unwrap_or_default();
+    let held_msat = state.held_msat(pay_hash) + amount_msat;
+    let issue = match fixed_msat {
+        Some(msat) => check_amount(msat, config.overpayment_percent.1, total_msat, held_msat),
+        None => check_bounds(&record.policy, total_msat, held_msat),
+    };
+    if let Some(issue) = issue {
+        warn!("{} for payment_hash: {}, rejecting!", issue, pay_hash);
+        return Ok(json!({"result": "fail"}));
+    }
+    // `any` amount invoices are complete once the total announced by the payer arrived
+    let invoice_msat = fixed_msat.unwrap_or(total_msat);
+    state.set_invoice_msat(pay_hash, invoice_msat);
+    state.count_htlc(pay_hash, short_channel_id, id);
+    notify(
+        state,
+        NOTIFICATION_HTLC_HELD,
+        json!({
+            "payment_hash": pay_hash,
+            "htlc": v.get("htlc"),
+            "held_msat": state.held_msat(pay_hash),
+            "invoice_msat": invoice_msat,
+        }),
+    );
+
+    loop {
+        let hodlstate = rx.borrow_and_update().clone();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        // only release the htlcs once all parts of the payment arrived
+        let fully_held = state.is_fully_held(pay_hash);
+
+        // a decided payment is released below, the preimage may be out already
+        let decided = matches!(
+            hodlstate,
+            Some(Hodlstate::Accepted) | Some(Hodlstate::Settled)
+        ) && fully_held;
+        if expires_at <= now && !decided {
+            warn!(
+                "hodling invoice with payment_hash: {} expired, rejecting!",
+                pay_hash
+            );
+            if state.announce(pay_hash, Hodlstate::Expired) {
+                let result = transition_record(
+                    state,
+                    pay_hash,
+                    Hodlstate::Expired,
+                    Some("invoice expired".to_string()),
+                    |_| Ok(()),
+                )
+                .await;
+                decided_already(state, pay_hash, result)?;
+                continue;
+            }
+            return Ok(json!({"result": "fail"}));
+        }
+
+        if fully_held
+            && matches!(hodlstate, None | Some(Hodlstate::Open))
+            && state.announce(pay_hash, Hodlstate::Held)
+        {
+            let result =
+                transition_record(state, pay_hash, Hodlstate::Held, None, |_| Ok(())).await;
+            decided_already(state, pay_hash, result)?;
+            continue;
+        }
+
+        let timed_out = if deadline_blockheight <= *state.blockheight.lock() {
+            Some(format!(
+                "deadline_blockheight {} reached",
+                deadline_blockheight
+            ))
+        } else if hold_until <= now {
+            Some(format!("held for more than {}s", max_hold_seconds))
+        } else {
+            None
+        };
+        if let Some(reason) = timed_out {
+            match hodlstate {
+                // already decided, release them below
+                Some(Hodlstate::Accepted) | Some(Hodlstate::Settled) if fully_held => (),
+                None
+                | Some(Hodlstate::Open)
+                | Some(Hodlstate::Held)
+                | Some(Hodlstate::Accepted) => {
+                    // a partial payment can only be failed
+                    let action = if record.policy.on_timeout == OnTimeout::Accept && fully_held {
+                        Hodlstate::Accepted
+                    } else {
+                        Hodlstate::Expired
+                    };
+                    if state.announce(pay_hash, action.clone()) {
+                        warn!(
+                            "htlc timed out for payment_hash: {} ({}), {} it!",
+                            pay_hash,
+                            reason,
+                            if action == Hodlstate::Accepted {
+                                "accepting"
                             } else {
-                                if invoice.is_none() {
-                                    invoice = Some(
-                                        listinvoices(&rpc_path, None, Some(pay_hash.to_string()))
-                                            .await?
-                                            .invoices
-                                            .first()
-                                            .ok_or(anyhow!("invoice not found"))?
-                                            .clone(),
-                                    );
-                                }
-
-                                let now = SystemTime::now()
-                                    .duration_since(UNIX_EPOCH)
-                                    .unwrap()
-                                    .as_secs();
-
-                                if invoice.as_ref().unwrap().expires_at <= now {
-                                    warn!(
-                                        "hodling invoice with payment_hash: {} expired, rejecting!",
-                                        pay_hash
-                                    );
-                                    return Ok(json!({"result": "fail"}));
-                                }
-
-                                if cltv_expiry - cltv_delta
-                                    <= plugin.state().blockheight.lock().clone() + CLTV_HODL as u64
-                                {
-                                    warn!(
-                                        "htlc timed out for payment_hash: {}, rejecting!",
-                                        pay_hash
-                                    );
-                                    return Ok(json!({"result": "fail"}));
-                                }
-
-                                let hodlstate = Hodlstate::from_str(
-                                    resp.datastore.first().unwrap().string.as_ref().unwrap(),
-                                )
-                                .unwrap();
-                                // debug!(
-                                //     "htlc_handler: payment_hash: {} hodlstate: {:?}",
-                                //     pay_hash, resp,
-                                // );
-                                match hodlstate {
-                                    Hodlstate::Hodl => {
-                                        debug!("hodling invoice with payment_hash: {}", pay_hash);
-                                    }
-                                    Hodlstate::Accept => {
-                                        debug!("accepted invoice with payment_hash: {}", pay_hash);
-                                        return Ok(json!({"result": "continue"}));
-                                    }
-                                    Hodlstate::Reject => {
-                                        debug!("rejected invoice with payment_hash: {}", pay_hash);
-                                        return Ok(json!({"result": "fail"}));
-                                    }
-                                }
+                                "rejecting"
                             }
+                        );
+                        let result =
+                            transition_record(state, pay_hash, action, Some(reason), |_| Ok(()))
+                                .await;
+                        decided_already(state, pay_hash, result)?;
+                        continue;
+                    }
+                    if action == Hodlstate::Expired {
+                        return Ok(json!({"result": "fail"}));
+                    }
+                    // another part is accepting the payment right now
+                    if rx.changed().await.is_err() {
+                        return Err(anyhow!(
+                            "hodlstate channel closed for payment_hash: {}",
+                            pay_hash
+                        ));
+                    }
+                    continue;
+                }
+                _ => {
+                    warn!(
+                        "htlc timed out for payment_hash: {} ({}), rejecting!",
+                        pay_hash, reason
+                    );
+                    return Ok(json!({"result": "fail"}));
+                }
+            }
+        }
+
+        match hodlstate {
+            None | Some(Hodlstate::Open) | Some(Hodlstate::Held) => {
+                debug!(
+                    "hodling invoice with payment_hash: {} held_msat: {} complete: {}",
+                    pay_hash,
+                    state.held_msat(pay_hash),
+                    fully_held
+                );
+            }
+            Some(Hodlstate::Accepted) if !fully_held => {
+                debug!(
+                    "waiting for remaining parts of payment_hash: {} held_msat: {}",
+                    pay_hash,
+                    state.held_msat(pay_hash)
+                );
+            }
+            Some(Hodlstate::Accepted) | Some(Hodlstate::Settled) => {
+                // settled with `hodlvoice-settle`, lightningd doesn't know the preimage of
+                // invoices created from a payment_hash and resolves the others itself
+                let preimage = match get_record(rpc, pay_hash).await? {
+                    Some(r) => r.external.and(r.preimage),
+                    None => return Err(anyhow!("record gone for payment_hash: {}", pay_hash)),
+                };
+                match preimage {
+                    Some(preimage) => {
+                        debug!("settling invoice with payment_hash: {}", pay_hash);
+                        if state.announce(pay_hash, Hodlstate::Settled) {
+                            let result = transition_record(
+                                state,
+                                pay_hash,
+                                Hodlstate::Settled,
+                                Some("htlcs resolved with preimage".to_string()),
+                                |_| Ok(()),
+                            )
+                            .await;
+                            decided_already(state, pay_hash, result)?;
                         }
-                        Err(e) => {
-                            debug!(
-                                "{} not our invoice: payment_hash: {}",
-                                e.to_string(),
-                                pay_hash
-                            );
-                            return Ok(json!({"result": "continue"}));
-                        }
+                        return Ok(json!({"result": "resolve", "payment_key": preimage}));
+                    }
+                    None => {
+                        debug!("accepted invoice with payment_hash: {}", pay_hash);
+                        return Ok(json!({"result": "continue"}));
                     }
                 }
-                time::sleep(Duration::from_secs(2)).await;
+            }
+            Some(Hodlstate::Canceled) => {
+                debug!("canceled invoice with payment_hash: {}", pay_hash);
+                return Ok(json!({"result": "fail"}));
+            }
+            Some(Hodlstate::Expired) => {
+                debug!("expired invoice with payment_hash: {}", pay_hash);
+                return Ok(json!({"result": "fail"}));
+            }
+        }
+
+        // woken up by a state change, a new block, the invoice expiring or the hold timing out
+        let wait = expires_at.min(hold_until).saturating_sub(now);
+        match time::timeout(Duration::from_secs(wait), rx.changed()).await {
+            Ok(Ok(())) | Err(_) => (),
+            Ok(Err(_)) => {
+                return Err(anyhow!(
+                    "hodlstate channel closed for payment_hash: {}",
+                    pay_hash
+                ))
             }
         }
     }
-    Ok(json!({"result": "continue"}))
+}
+
+// payments that can never settle are failed right away instead of locking up liquidity
+fn check_amount(
+    invoice_msat: u64,
+    overpayment_percent: u64,
+    total_msat: u64,
+    held_msat: u64,
+) -> Option<String> {
+    let max_msat =
+        invoice_msat.saturating_add(invoice_msat.saturating_mul(overpayment_percent) / 100);
+    if total_msat < invoice_msat {
+        Some(format!(
+            "underpaid: total of {}msat is less than the invoice amount of {}msat",
+            total_msat, invoice_msat
+        ))
+    } else if total_msat > max_msat || held_msat > max_msat {
+        Some(format!(
+            "overpaid: {}msat is more than the {}msat allowed for an invoice of {}msat",
+            total_msat.max(held_msat),
+            max_msat,
+            invoice_msat
+        ))
+    } else {
+        None
+    }
+}
+
+// the payer picks the amount of `any` invoices, within the bounds set when creating it
+fn check_bounds(policy: &Policy, total_msat: u64, held_msat: u64) -> Option<String> {
+    if policy.min_msat.map_or(false, |min| total_msat < min) {
+        Some(format!(
+            "underpaid: total of {}msat is less than the minimum of {}msat",
+            total_msat,
+            policy.min_msat.unwrap_or_default()
+        ))
+    } else if held_msat > total_msat {
+        Some(format!(
+            "overpaid: {}msat held is more than the total of {}msat",
+            held_msat, total_msat
+        ))
+    } else if policy.max_msat.map_or(false, |max| total_msat > max) {
+        Some(format!(
+            "overpaid: total of {}msat is more than the maximum of {}msat",
+            total_msat,
+            policy.max_msat.unwrap_or_default()
+        ))
+    } else {
+        None
+    }
+}
+
+// a decision made while we timed out wins, go on with the stored state
+fn decided_already<T>(
+    state: &PluginState,
+    pay_hash: &str,
+    result: Result<T, Error>,
+) -> Result<(), Error> {
+    match result {
+        Ok(_) => Ok(()),
+        Err(e) => match refused_by(&e) {
+            Some(current) => {
+                info!("{}, keeping it", e);
+                state.update_hodlstate(pay_hash, current);
+                Ok(())
+            }
+            None => Err(e),
+        },
+    }
 }
 
 pub async fn block_added(plugin: Plugin<PluginState>, v: serde_json::Value) -> Result<(), Error> {
-    match v.get("block") {
-        Some(block) => match block.get("height") {
-            Some(h) => *plugin.state().blockheight.lock() = h.as_u64().unwrap(),
+    handle_block(plugin.state(), v)
+}
+
+/// Track the block height from a `block_added` notification.
+pub fn handle_block(state: &PluginState, v: serde_json::Value) -> Result<(), Error> {
+    let height = match v.get("block") {
+        Some(block) => match block.get("height").and_then(|h| h.as_u64()) {
+            Some(h) => h,
             None => return Err(anyhow!("could not find height for block")),
         },
         None => return Err(anyhow!("could not read block notification")),
     };
+    let previous = std::mem::replace(&mut *state.blockheight.lock(), height);
+    if height < previous {
+        warn!(
+            "reorg detected, block height went from {} down to {}",
+            previous, height
+        );
+    }
+    // the held htlcs re-check their deadlines against the new height, after a reorg too
+    state.wake_all();
+    schedule_expiry(state);
+    schedule_gc(state, height);
+    Ok(())
+}
+
+// expire the hold-invoices without htlcs in the background
+fn schedule_expiry(state: &PluginState) {
+    let state = state.clone();
+    tokio::spawn(async move {
+        match expire_records(&state).await {
+            Ok(0) => (),
+            Ok(expired) => info!("{} hold-invoices expired", expired),
+            Err(e) => warn!("Error expiring hold-invoices: {}", e),
+        }
+    });
+}
+
+pub async fn invoice_payment(
+    plugin: Plugin<PluginState>,
+    v: serde_json::Value,
+) -> Result<(), Error> {
+    handle_invoice_payment(plugin.state(), v).await
+}
+
+/// Mark an accepted hold-invoice settled once lightningd reports it paid.
+pub async fn handle_invoice_payment(
+    state: &PluginState,
+    v: serde_json::Value,
+) -> Result<(), Error> {
+    let preimage = match v
+        .get("invoice_payment")
+        .and_then(|p| p.get("preimage"))
+        .and_then(|p| p.as_str())
+    {
+        Some(p) => {
+            hex::decode(p).map_err(|e| anyhow!("invalid preimage in invoice_payment: {}", e))?
+        }
+        None => return Err(anyhow!("could not read invoice_payment notification")),
+    };
+    let pay_hash = hex::encode(sha256::Hash::hash(&preimage).into_inner());
+    match get_record(state.rpc.as_ref(), &pay_hash).await? {
+        Some(record) => {
+            if record_payment(state, &record).await? {
+                info!("hold-invoice with payment_hash: {} settled", pay_hash);
+            }
+        }
+        None => debug!("not our invoice: payment_hash: {}", pay_hash),
+    }
+    Ok(())
+}
+
+/// Read the current block height, so we don't have to wait for the next block to check deadlines.
+pub async fn init_blockheight(state: &PluginState) -> Result<(), Error> {
+    let blockheight = getinfo(state.rpc.as_ref()).await?.blockheight as u64;
+    info!("current block height: {}", blockheight);
+    *state.blockheight.lock() = blockheight;
     Ok(())
 }
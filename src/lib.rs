@@ -1,48 +1,116 @@
 use std::{
     fmt,
-    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
 };
 
-use anyhow::{anyhow, Error};
+use anyhow::Error;
+use bitcoin::hashes::{sha256, Hash};
+use bolt11::{InvoiceDescription, UnsignedInvoice};
 use cln_plugin::Plugin;
 use cln_rpc::{
     model::{
-        DatastoreMode, DatastoreRequest, DatastoreResponse, InvoiceRequest, InvoiceResponse,
-        ListdatastoreRequest, ListdatastoreResponse, ListinvoicesRequest, ListinvoicesResponse,
+        DatastoreMode, DatastoreRequest, DatastoreResponse, DeldatastoreRequest,
+        DeldatastoreResponse, DelinvoiceRequest, DelinvoiceResponse, DelinvoiceStatus,
+        GetinfoRequest, GetinfoResponse, InvoiceRequest, InvoiceResponse, ListconfigsRequest,
+        ListconfigsResponse, ListdatastoreRequest, ListdatastoreResponse,
+        ListinvoicesInvoicesStatus, ListinvoicesRequest, ListinvoicesResponse, SigninvoiceRequest,
+        SigninvoiceResponse,
     },
     primitives::{Amount, AmountOrAny},
-    ClnRpc, Request, Response,
+    Request, Response,
 };
 use config::PluginState;
+use error::{error_code, lightningd_error, rpc_error, unexpected_response, HodlError};
+use log::info;
+use params::{invalid_params, AddRequest, HodlRefRequest, Params, SettleRequest};
+use record::{
+    external_label, get_record, resolve_label, resolve_ref, store_record, transition_record,
+    HodlRecord, HodlRef, OnTimeout, Policy,
+};
+use rpc::Rpc;
+use serde::{Deserialize, Serialize};
 use serde_json::json;
 
+pub mod bolt11;
+pub mod callback;
 pub mod config;
+pub mod error;
+pub mod events;
+pub mod gc;
 pub mod hooks;
+pub mod lookup;
+pub mod params;
+pub mod record;
+pub mod rpc;
 
 pub const PLUGIN_NAME: &str = "hodlvoice";
-pub const CLTV_HODL: u32 = 200;
+pub const DEFAULT_HOLD_BLOCKS: u32 = 144;
+pub const DEFAULT_SAFETY_BLOCKS: u32 = 18;
+// average time between blocks, used to compare block counts with the invoice expiry
+pub const BLOCK_SECONDS: u64 = 600;
+pub const DEFAULT_EXPIRY: u64 = 604_800;
 
-#[derive(Debug, Clone)]
+/// Where the money of a hold-invoice is. The plain datastore entries of older versions
+/// used `hodl`, `accept` and `reject`, `from_str` still understands these.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum Hodlstate {
-    Hodl,
-    Reject,
-    Accept,
+    // invoice created, nothing held yet
+    Open,
+    // the full amount is locked in htlcs, waiting for a decision
+    Held,
+    // htlcs released (or to be released once complete), waiting for the payment to settle
+    Accepted,
+    // the preimage was revealed, the money is ours
+    Settled,
+    // rejected, held htlcs were failed
+    Canceled,
+    Expired,
 }
 impl Hodlstate {
     pub fn to_string(&self) -> String {
         match self {
-            Hodlstate::Hodl => "hodl".to_string(),
-            Hodlstate::Reject => "reject".to_string(),
-            Hodlstate::Accept => "accept".to_string(),
+            Hodlstate::Open => "open".to_string(),
+            Hodlstate::Held => "held".to_string(),
+            Hodlstate::Accepted => "accepted".to_string(),
+            Hodlstate::Settled => "settled".to_string(),
+            Hodlstate::Canceled => "canceled".to_string(),
+            Hodlstate::Expired => "expired".to_string(),
         }
     }
+
+    /// Whether a stored record in this state may change to `next`. A decision can't be
+    /// changed, accepted hold-invoices can only still settle or time out.
+    pub fn can_transition_to(&self, next: &Hodlstate) -> bool {
+        matches!(
+            (self, next),
+            (
+                Hodlstate::Open,
+                Hodlstate::Held | Hodlstate::Accepted | Hodlstate::Canceled | Hodlstate::Expired
+            ) | (
+                Hodlstate::Held,
+                Hodlstate::Accepted | Hodlstate::Canceled | Hodlstate::Expired
+            ) | (Hodlstate::Accepted, Hodlstate::Settled | Hodlstate::Expired)
+        )
+    }
+
+    /// Nothing will happen to the hold-invoice anymore.
+    pub fn is_final(&self) -> bool {
+        matches!(
+            self,
+            Hodlstate::Settled | Hodlstate::Canceled | Hodlstate::Expired
+        )
+    }
 }
 impl Hodlstate {
     pub fn from_str(s: &str) -> Option<Hodlstate> {
         match s.to_lowercase().as_str() {
-            "hodl" => Some(Hodlstate::Hodl),
-            "reject" => Some(Hodlstate::Reject),
-            "accept" => Some(Hodlstate::Accept),
+            "open" | "hodl" => Some(Hodlstate::Open),
+            "held" => Some(Hodlstate::Held),
+            "accepted" | "accept" => Some(Hodlstate::Accepted),
+            "settled" => Some(Hodlstate::Settled),
+            "canceled" | "reject" => Some(Hodlstate::Canceled),
+            "expired" => Some(Hodlstate::Expired),
             _ => None,
         }
     }
@@ -50,215 +118,445 @@ impl Hodlstate {
 impl fmt::Display for Hodlstate {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            Hodlstate::Hodl => write!(f, "Hodl"),
-            Hodlstate::Reject => write!(f, "Reject"),
-            Hodlstate::Accept => write!(f, "Accept"),
+            Hodlstate::Open => write!(f, "Open"),
+            Hodlstate::Held => write!(f, "Held"),
+            Hodlstate::Accepted => write!(f, "Accepted"),
+            Hodlstate::Settled => write!(f, "Settled"),
+            Hodlstate::Canceled => write!(f, "Canceled"),
+            Hodlstate::Expired => write!(f, "Expired"),
         }
     }
 }
 
+/// A hold invoice created from a `payment_hash` only. lightningd does not know about
+/// it, so we keep what the htlc_accepted hook needs in our record.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExternalInvoice {
+    pub bolt11: String,
+    pub payment_secret: String,
+    // `None` for `any` amount
+    pub amount_msat: Option<u64>,
+    pub expires_at: u64,
+}
+
 pub async fn hodlvoiceadd(
     plugin: Plugin<PluginState>,
     args: serde_json::Value,
 ) -> Result<serde_json::Value, Error> {
-    let rpc_path = &make_rpc_path(&plugin);
-    let valid_keys = vec![
-        "amount_msat",
-        "description",
-        "label",
-        "expiry",
-        "fallbacks",
-        "preimage",
-        "exposeprivatechannels",
-        "deschashonly",
-    ];
-
-    let config = plugin.state().config.lock().clone();
-
-    let my_invoice;
-    match args {
-        serde_json::Value::Object(ar) => {
-            for k in ar.keys() {
-                if !valid_keys.contains(&k.as_str()) {
-                    return Err(anyhow!("Invalid argument: {}", k));
-                }
-            }
+    add_hodlvoice(plugin.state(), args).await.map_err(rpc_error)
+}
 
-            let amount_msat;
-            match ar.get("amount_msat") {
-                Some(amt) => {
-                    amount_msat = Amount::from_msat(
-                        amt.as_u64()
-                            .ok_or(anyhow!("invalid string for amount_msat"))?,
-                    )
-                }
-                None => return Err(anyhow!("Missing amount_msat")),
-            }
+/// Create a hold-invoice, the `hodlvoice-add` rpc method without the plugin.
+pub async fn add_hodlvoice(
+    state: &PluginState,
+    args: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    let rpc = state.rpc.as_ref();
+    let config = state.config.lock().clone();
+    let req = AddRequest::parse(args)?;
 
-            let description;
-            match ar.get("description") {
-                Some(desc) => {
-                    description = desc
-                        .as_str()
-                        .ok_or(anyhow!("invalid string for description"))?
-                        .to_string();
-                }
-                None => return Err(anyhow!("Missing description")),
-            }
+    let amount_msat = parse_amount(&req.amount_msat, "amount_msat", true)?;
+    let min_msat = match &req.min_msat {
+        Some(m) => Some(amount_value(parse_amount(m, "min_msat", false)?)),
+        None => None,
+    };
+    let max_msat = match &req.max_msat {
+        Some(m) => Some(amount_value(parse_amount(m, "max_msat", false)?)),
+        None => None,
+    };
+    if (min_msat.is_some() || max_msat.is_some()) && !matches!(amount_msat, AmountOrAny::Any) {
+        return Err(invalid_params(
+            "`min_msat` and `max_msat` only work with amount_msat `any`",
+        ));
+    }
+    if let (Some(min), Some(max)) = (min_msat, max_msat) {
+        if min > max {
+            return Err(invalid_params("min_msat is bigger than max_msat"));
+        }
+    }
+    if req.cltv.is_some() {
+        return Err(invalid_params(
+            "`cltv` is set from the plugin options, use `hold_blocks` instead",
+        ));
+    }
 
-            let label;
-            match ar.get("label") {
-                Some(lbl) => {
-                    label = lbl
-                        .as_str()
-                        .ok_or(anyhow!("invalid string for label"))?
-                        .to_string();
-                }
-                None => return Err(anyhow!("Missing label")),
-            }
+    let hold_blocks = req.hold_blocks.unwrap_or(config.hold_blocks.1);
+    if hold_blocks == 0 {
+        return Err(invalid_params("hold_blocks must be positive"));
+    }
+    // htlcs are failed once the invoice expires, so there is no point in holding longer
+    if hold_blocks as u64 * BLOCK_SECONDS > req.expiry.unwrap_or(DEFAULT_EXPIRY) {
+        return Err(invalid_params(format!(
+            "hold_blocks of {} (~{}s) exceeds the invoice expiry of {}s",
+            hold_blocks,
+            hold_blocks as u64 * BLOCK_SECONDS,
+            req.expiry.unwrap_or(DEFAULT_EXPIRY)
+        )));
+    }
+    let on_timeout = req.on_timeout.unwrap_or(config.on_timeout.1.clone());
+    if on_timeout == OnTimeout::Accept && req.payment_hash.is_some() {
+        return Err(invalid_params(
+            "lightningd does not know the preimage, `on_timeout` must be `reject` with `payment_hash`",
+        ));
+    }
 
-            let expiry = match ar.get("expiry") {
-                Some(exp) => Some(exp.as_u64().ok_or(anyhow!("expiry must be an integer"))?),
-                None => None,
-            };
-
-            let fallbacks = match ar.get("fallbacks") {
-                Some(o) => {
-                    let mut fb_vec: Vec<String> = Vec::new();
-                    for fb in o
-                        .as_array()
-                        .ok_or(anyhow!("fallbacks must be an array of Strings"))?
-                    {
-                        fb_vec.push(
-                            fb.as_str()
-                                .ok_or(anyhow!("invalid input for fallback string: {}", fb))?
-                                .to_string(),
-                        )
-                    }
-                    Some(fb_vec)
-                }
-                None => None,
-            };
-
-            let preimage = match ar.get("preimage") {
-                Some(prei) => Some(
-                    prei.as_str()
-                        .ok_or(anyhow!("invalid string for preimage"))?
-                        .to_string(),
-                ),
-                None => None,
-            };
-
-            let exposeprivatechannels = match ar.get("exposeprivatechannels") {
-                Some(h) => Some(
-                    h.as_bool()
-                        .ok_or(anyhow!("exposeprivatechannels must be a bool"))?,
-                ),
-                None => None,
-            };
-
-            let deschashonly = match ar.get("deschashonly") {
-                Some(dp) => Some(dp.as_bool().ok_or(anyhow!("deschashonly must be a bool"))?),
-                None => None,
-            };
-
-            my_invoice = invoice(
-                rpc_path,
+    let max_hold_seconds = req.max_hold_seconds.unwrap_or(config.max_hold_seconds.1);
+    if max_hold_seconds == 0 {
+        return Err(invalid_params("max_hold_seconds must be positive"));
+    }
+
+    let policy = Policy {
+        safety_blocks: config.safety_blocks.1,
+        hold_blocks,
+        on_timeout,
+        max_hold_seconds: Some(max_hold_seconds),
+        min_msat,
+        max_msat,
+    };
+    let cltv = config.cltv_delta.1 as u32 + policy.safety_blocks + policy.hold_blocks;
+
+    // lightningd doesn't know the labels of invoices created from a payment_hash
+    let duplicate = match req.payment_hash {
+        Some(_) => resolve_label(rpc, &req.label).await?,
+        None => external_label(rpc, &req.label).await?,
+    };
+    if duplicate.is_some() {
+        return Err(invalid_params(format!(
+            "label {} is already used",
+            req.label
+        )));
+    }
+
+    let (record, mut result) = match req.payment_hash {
+        Some(ph) => {
+            let ph = ph.to_lowercase();
+            if req.preimage.is_some() {
+                return Err(invalid_params(
+                    "Cannot use `payment_hash` together with `preimage`",
+                ));
+            }
+            if req.fallbacks.is_some() || req.exposeprivatechannels.is_some() {
+                return Err(invalid_params(
+                    "`fallbacks` and `exposeprivatechannels` are not supported with `payment_hash`",
+                ));
+            }
+            let (external, created_at) = external_invoice(
+                rpc,
+                &config.network,
                 amount_msat,
-                description,
-                label,
-                expiry,
-                fallbacks,
-                preimage,
-                exposeprivatechannels,
-                Some(CLTV_HODL + config.cltv_delta.1 as u32),
-                deschashonly,
+                req.description,
+                req.expiry,
+                &ph,
+                cltv,
+                req.deschashonly,
             )
             .await?;
+            let result = json!({
+                "bolt11": external.bolt11,
+                "payment_hash": ph,
+                "payment_secret": external.payment_secret,
+                "expires_at": external.expires_at,
+            });
+            let mut record = HodlRecord::new(ph, Some(req.label), created_at, policy);
+            record.external = Some(external);
+            (record, result)
         }
-        other => return Err(anyhow!("Invalid arguments: {}", other.to_string())),
-    }
+        None => {
+            let my_invoice = invoice(
+                rpc,
+                amount_msat,
+                req.description,
+                req.label.clone(),
+                req.expiry,
+                req.fallbacks,
+                req.preimage,
+                req.exposeprivatechannels,
+                Some(cltv),
+                req.deschashonly,
+            )
+            .await?;
+            let record = HodlRecord::new(
+                my_invoice.payment_hash.to_string(),
+                Some(req.label),
+                bolt11::timestamp(&my_invoice.bolt11)
+                    .unwrap_or(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs()),
+                policy,
+            );
+            (record, json!(my_invoice))
+        }
+    };
 
-    let _datastore = datastore(
-        rpc_path,
-        vec![PLUGIN_NAME.to_string(), my_invoice.payment_hash.to_string()],
-        Some(Hodlstate::Hodl.to_string()),
-        None,
-        Some(DatastoreMode::MUST_CREATE),
-        None,
-    )
-    .await?;
+    store_record(rpc, &record, DatastoreMode::MUST_CREATE, None).await?;
+
+    // the htlcs of a payment arriving now would be held until this block height at the latest
+    let blockheight = *state.blockheight.lock();
+    result["hold_blocks"] = json!(record.policy.hold_blocks);
+    result["deadline_blockheight"] = if blockheight > 0 {
+        json!(blockheight + record.policy.hold_blocks as u64)
+    } else {
+        serde_json::Value::Null
+    };
 
-    Ok(json!(my_invoice))
+    Ok(result)
 }
 
 pub async fn hodlvoiceaccept(
     plugin: Plugin<PluginState>,
     args: serde_json::Value,
 ) -> Result<serde_json::Value, Error> {
-    let rpc_path = &make_rpc_path(&plugin);
-    match args {
-        serde_json::Value::Array(a) => {
-            if a.len() != 1 {
-                return Err(anyhow!("Please provide exactly one `payment_hash`"));
-            } else {
-                match a.first().unwrap() {
-                    serde_json::Value::String(i) => {
-                        datastore(
-                            rpc_path,
-                            vec![PLUGIN_NAME.to_string(), i.clone()],
-                            Some(Hodlstate::Accept.to_string()),
-                            None,
-                            Some(DatastoreMode::MUST_REPLACE),
-                            None,
-                        )
-                        .await?;
+    accept_hodlvoice(plugin.state(), args)
+        .await
+        .map_err(rpc_error)
+}
+
+/// The `hodlvoice-accept` rpc method without the plugin.
+pub async fn accept_hodlvoice(
+    state: &PluginState,
+    args: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    let (hodl_refs, batch) = HodlRefRequest::refs(args)?;
+    let mut results = Vec::new();
+    for hodl_ref in hodl_refs {
+        let result = match resolve_ref(state.rpc.as_ref(), &hodl_ref).await {
+            Ok(pay_hash) => {
+                transition_record(state, &pay_hash, Hodlstate::Accepted, None, |record| {
+                    if record.external.is_some() {
+                        return Err(invalid_params(format!(
+                            "lightningd does not know the preimage for {}, use `{}-settle`",
+                            record.payment_hash, PLUGIN_NAME
+                        )));
                     }
-                    _ => return Err(anyhow!("invalid string for accepting hold-invoice")),
-                };
+                    Ok(())
+                })
+                .await
             }
-        }
-        _ => return Err(anyhow!("invalid arguments")),
-    };
-
-    Ok(json!({"result": "success"}))
+            Err(e) => Err(e),
+        };
+        results.push((hodl_ref, result));
+    }
+    batch_result(results, batch)
 }
 
 pub async fn hodlvoicereject(
     plugin: Plugin<PluginState>,
     args: serde_json::Value,
 ) -> Result<serde_json::Value, Error> {
-    let rpc_path = &make_rpc_path(&plugin);
-    match args {
-        serde_json::Value::Array(a) => {
-            if a.len() != 1 {
-                return Err(anyhow!("Please provide exactly one `payment_hash`"));
-            } else {
-                match a.first().unwrap() {
-                    serde_json::Value::String(i) => {
-                        datastore(
-                            rpc_path,
-                            vec![PLUGIN_NAME.to_string(), i.clone()],
-                            Some(Hodlstate::Reject.to_string()),
-                            None,
-                            Some(DatastoreMode::MUST_REPLACE),
-                            None,
-                        )
-                        .await?;
-                    }
-                    _ => return Err(anyhow!("invalid string for rejecting hold-invoice")),
-                };
+    reject_hodlvoice(plugin.state(), args)
+        .await
+        .map_err(rpc_error)
+}
+
+/// The `hodlvoice-reject` rpc method without the plugin.
+pub async fn reject_hodlvoice(
+    state: &PluginState,
+    args: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    let (hodl_refs, batch) = HodlRefRequest::refs(args)?;
+    let mut results = Vec::new();
+    for hodl_ref in hodl_refs {
+        let result = match resolve_ref(state.rpc.as_ref(), &hodl_ref).await {
+            Ok(pay_hash) => {
+                transition_record(state, &pay_hash, Hodlstate::Canceled, None, |_| Ok(())).await
             }
+            Err(e) => Err(e),
+        };
+        results.push((hodl_ref, result));
+    }
+    batch_result(results, batch)
+}
+
+pub async fn hodlvoicecancel(
+    plugin: Plugin<PluginState>,
+    args: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    cancel_hodlvoice(plugin.state(), args)
+        .await
+        .map_err(rpc_error)
+}
+
+/// The `hodlvoice-cancel` rpc method without the plugin: reject the hold-invoice and
+/// delete its invoice, so it can't be paid anymore and the label can be used again.
+pub async fn cancel_hodlvoice(
+    state: &PluginState,
+    args: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    let (hodl_refs, batch) = HodlRefRequest::refs(args)?;
+    let mut results = Vec::new();
+    for hodl_ref in hodl_refs {
+        let result = match resolve_ref(state.rpc.as_ref(), &hodl_ref).await {
+            Ok(pay_hash) => cancel_one(state, &pay_hash).await,
+            Err(e) => Err(e),
+        };
+        results.push((hodl_ref, result));
+    }
+    batch_result(results, batch)
+}
+
+async fn cancel_one(state: &PluginState, pay_hash: &str) -> Result<HodlRecord, Error> {
+    let rpc = state.rpc.as_ref();
+    let record = match get_record(rpc, pay_hash).await? {
+        // rejected or timed out before, only the invoice is left to delete
+        Some(record) if matches!(record.state, Hodlstate::Canceled | Hodlstate::Expired) => record,
+        Some(_) => {
+            transition_record(
+                state,
+                pay_hash,
+                Hodlstate::Canceled,
+                Some("canceled".to_string()),
+                |_| Ok(()),
+            )
+            .await?
         }
-        _ => return Err(anyhow!("invalid arguments")),
+        None => return Err(HodlError::UnknownHodlvoice(pay_hash.to_string()).into()),
     };
 
+    // invoices created from a payment_hash only exist in our record
+    if let Some(inv) = listinvoices(rpc, None, Some(pay_hash.to_string()))
+        .await?
+        .invoices
+        .into_iter()
+        .next()
+    {
+        let status = match inv.status {
+            ListinvoicesInvoicesStatus::UNPAID => DelinvoiceStatus::UNPAID,
+            ListinvoicesInvoicesStatus::EXPIRED => DelinvoiceStatus::EXPIRED,
+            ListinvoicesInvoicesStatus::PAID => {
+                return Err(HodlError::InvoicePaid(pay_hash.to_string()).into())
+            }
+        };
+        delinvoice(rpc, inv.label.clone(), status).await?;
+        info!(
+            "deleted invoice {} of canceled hold-invoice {}",
+            inv.label, pay_hash
+        );
+    }
+    Ok(record)
+}
+
+// one hold-invoice, or a list of them to decide in one call. Positional arguments can
+// be a payment_hash or a label, keyword arguments are either `payment_hash` or `label`.
+// a single decision fails the call, a batch reports every payment_hash on its own
+fn batch_result(
+    results: Vec<(HodlRef, Result<HodlRecord, Error>)>,
+    batch: bool,
+) -> Result<serde_json::Value, Error> {
+    if !batch {
+        return match results.into_iter().next() {
+            Some((_, Err(e))) => Err(e),
+            _ => Ok(json!({"result": "success"})),
+        };
+    }
+    let failed = results.iter().filter(|(_, r)| r.is_err()).count();
+    let results: Vec<serde_json::Value> = results
+        .into_iter()
+        .map(|(hodl_ref, result)| match result {
+            Ok(record) => json!({
+                "payment_hash": record.payment_hash,
+                "label": record.label,
+                "result": "success",
+                "state": record.state.to_string(),
+            }),
+            Err(e) => {
+                let mut entry = hodl_ref.to_json();
+                entry["result"] = json!("error");
+                entry["code"] = json!(error_code(&e));
+                entry["error"] = json!(e.to_string());
+                entry
+            }
+        })
+        .collect();
+    Ok(json!({
+        "succeeded": results.len() - failed,
+        "failed": failed,
+        "results": results,
+    }))
+}
+
+pub async fn hodlvoicesettle(
+    plugin: Plugin<PluginState>,
+    args: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    settle_hodlvoice(plugin.state(), args)
+        .await
+        .map_err(rpc_error)
+}
+
+/// The `hodlvoice-settle` rpc method without the plugin.
+pub async fn settle_hodlvoice(
+    state: &PluginState,
+    args: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    let req = SettleRequest::parse(args)?;
+    let pay_hash = req.payment_hash.to_lowercase();
+    let preimage = hex::decode(&req.preimage)
+        .map_err(|e| invalid_params(format!("invalid hex string for preimage: {}", e)))?;
+    if hex::encode(sha256::Hash::hash(&preimage).into_inner()) != pay_hash {
+        return Err(invalid_params("preimage does not match payment_hash"));
+    }
+    transition_record(state, &pay_hash, Hodlstate::Accepted, None, |record| {
+        // lightningd has the preimage of its own invoices
+        if record.external.is_some() {
+            record.preimage = Some(hex::encode(&preimage));
+        }
+        Ok(())
+    })
+    .await?;
+
     Ok(json!({"result": "success"}))
 }
 
+async fn external_invoice(
+    rpc: &dyn Rpc,
+    network: &str,
+    amount_msat: AmountOrAny,
+    description: String,
+    expiry: Option<u64>,
+    payment_hash: &str,
+    cltv: u32,
+    deschashonly: Option<bool>,
+) -> Result<(ExternalInvoice, u64), Error> {
+    let hash: [u8; 32] = hex::decode(payment_hash)
+        .map_err(|e| invalid_params(format!("invalid hex string for payment_hash: {}", e)))?
+        .try_into()
+        .map_err(|_| invalid_params("payment_hash must be 32 bytes"))?;
+    let payment_secret: [u8; 32] = rand::random();
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let expiry = expiry.unwrap_or(DEFAULT_EXPIRY);
+    let amount_msat = match amount_msat {
+        AmountOrAny::Amount(a) => Some(a.msat()),
+        AmountOrAny::Any => None,
+    };
+
+    let unsigned = UnsignedInvoice {
+        network: network.to_string(),
+        amount_msat,
+        timestamp,
+        payment_hash: hash,
+        payment_secret,
+        description: if deschashonly.unwrap_or(false) {
+            InvoiceDescription::Hash(description)
+        } else {
+            InvoiceDescription::Direct(description)
+        },
+        expiry,
+        min_final_cltv_expiry: cltv as u64,
+    }
+    .encode()?;
+
+    Ok((
+        ExternalInvoice {
+            bolt11: signinvoice(rpc, unsigned).await?.bolt11,
+            payment_secret: hex::encode(payment_secret),
+            amount_msat,
+            expires_at: timestamp + expiry,
+        },
+        timestamp,
+    ))
+}
+
 pub async fn invoice(
-    rpc_path: &PathBuf,
-    amount_msat: Amount,
+    rpc: &dyn Rpc,
+    amount_msat: AmountOrAny,
     description: String,
     label: String,
     expiry: Option<u64>,
@@ -268,10 +566,9 @@ pub async fn invoice(
     cltv: Option<u32>,
     deschashonly: Option<bool>,
 ) -> Result<InvoiceResponse, Error> {
-    let mut rpc = ClnRpc::new(&rpc_path).await?;
     let invoice_request = rpc
         .call(Request::Invoice(InvoiceRequest {
-            amount_msat: AmountOrAny::Amount(amount_msat),
+            amount_msat,
             description,
             label,
             expiry,
@@ -282,19 +579,18 @@ pub async fn invoice(
             deschashonly,
         }))
         .await
-        .map_err(|e| anyhow!("Error calling invoice: {:?}", e))?;
+        .map_err(|e| lightningd_error("invoice", e))?;
     match invoice_request {
         Response::Invoice(info) => Ok(info),
-        e => Err(anyhow!("Unexpected result in invoice: {:?}", e)),
+        e => Err(unexpected_response("invoice", e)),
     }
 }
 
 pub async fn listinvoices(
-    rpc_path: &PathBuf,
+    rpc: &dyn Rpc,
     label: Option<String>,
     payment_hash: Option<String>,
 ) -> Result<ListinvoicesResponse, Error> {
-    let mut rpc = ClnRpc::new(&rpc_path).await?;
     let invoice_request = rpc
         .call(Request::ListInvoices(ListinvoicesRequest {
             label,
@@ -303,22 +599,51 @@ pub async fn listinvoices(
             offer_id: None,
         }))
         .await
-        .map_err(|e| anyhow!("Error calling listinvoices: {:?}", e))?;
+        .map_err(|e| lightningd_error("listinvoices", e))?;
     match invoice_request {
         Response::ListInvoices(info) => Ok(info),
-        e => Err(anyhow!("Unexpected result in listinvoices: {:?}", e)),
+        e => Err(unexpected_response("listinvoices", e)),
+    }
+}
+
+pub async fn delinvoice(
+    rpc: &dyn Rpc,
+    label: String,
+    status: DelinvoiceStatus,
+) -> Result<DelinvoiceResponse, Error> {
+    let delinvoice_request = rpc
+        .call(Request::DelInvoice(DelinvoiceRequest {
+            label,
+            status,
+            desconly: None,
+        }))
+        .await
+        .map_err(|e| lightningd_error("delinvoice", e))?;
+    match delinvoice_request {
+        Response::DelInvoice(info) => Ok(info),
+        e => Err(unexpected_response("delinvoice", e)),
+    }
+}
+
+pub async fn signinvoice(rpc: &dyn Rpc, invstring: String) -> Result<SigninvoiceResponse, Error> {
+    let signinvoice_request = rpc
+        .call(Request::SignInvoice(SigninvoiceRequest { invstring }))
+        .await
+        .map_err(|e| lightningd_error("signinvoice", e))?;
+    match signinvoice_request {
+        Response::SignInvoice(info) => Ok(info),
+        e => Err(unexpected_response("signinvoice", e)),
     }
 }
 
 pub async fn datastore(
-    rpc_path: &PathBuf,
+    rpc: &dyn Rpc,
     key: Vec<String>,
     string: Option<String>,
     hex: Option<String>,
     mode: Option<DatastoreMode>,
     generation: Option<u64>,
 ) -> Result<DatastoreResponse, Error> {
-    let mut rpc = ClnRpc::new(&rpc_path).await?;
     let datastore_request = rpc
         .call(Request::Datastore(DatastoreRequest {
             key,
@@ -328,28 +653,133 @@ pub async fn datastore(
             generation,
         }))
         .await
-        .map_err(|e| anyhow!("Error calling datastore: {:?}", e))?;
+        .map_err(|e| lightningd_error("datastore", e))?;
     match datastore_request {
         Response::Datastore(info) => Ok(info),
-        e => Err(anyhow!("Unexpected result in datastore: {:?}", e)),
+        e => Err(unexpected_response("datastore", e)),
+    }
+}
+
+pub async fn deldatastore(rpc: &dyn Rpc, key: Vec<String>) -> Result<DeldatastoreResponse, Error> {
+    let datastore_request = rpc
+        .call(Request::DelDatastore(DeldatastoreRequest {
+            key,
+            generation: None,
+        }))
+        .await
+        .map_err(|e| lightningd_error("deldatastore", e))?;
+    match datastore_request {
+        Response::DelDatastore(info) => Ok(info),
+        e => Err(unexpected_response("deldatastore", e)),
+    }
+}
+
+pub async fn getinfo(rpc: &dyn Rpc) -> Result<GetinfoResponse, Error> {
+    let getinfo_request = rpc
+        .call(Request::Getinfo(GetinfoRequest {}))
+        .await
+        .map_err(|e| lightningd_error("getinfo", e))?;
+    match getinfo_request {
+        Response::Getinfo(info) => Ok(info),
+        e => Err(unexpected_response("getinfo", e)),
+    }
+}
+
+pub async fn listconfigs(
+    rpc: &dyn Rpc,
+    config: Option<String>,
+) -> Result<ListconfigsResponse, Error> {
+    let listconfigs_request = rpc
+        .call(Request::ListConfigs(ListconfigsRequest { config }))
+        .await
+        .map_err(|e| lightningd_error("listconfigs", e))?;
+    match listconfigs_request {
+        Response::ListConfigs(info) => Ok(info),
+        e => Err(unexpected_response("listconfigs", e)),
     }
 }
 
 pub async fn listdatastore(
-    rpc_path: &PathBuf,
+    rpc: &dyn Rpc,
     key: Option<Vec<String>>,
 ) -> Result<ListdatastoreResponse, Error> {
-    let mut rpc = ClnRpc::new(&rpc_path).await?;
     let datastore_request = rpc
         .call(Request::ListDatastore(ListdatastoreRequest { key }))
         .await
-        .map_err(|e| anyhow!("Error calling listdatastore: {:?}", e))?;
+        .map_err(|e| lightningd_error("listdatastore", e))?;
     match datastore_request {
         Response::ListDatastore(info) => Ok(info),
-        e => Err(anyhow!("Unexpected result in listdatastore: {:?}", e)),
+        e => Err(unexpected_response("listdatastore", e)),
+    }
+}
+
+/// Read an amount like lightningd's `invoice` does: msat as integer, `<n>msat`,
+/// `<n>sat` with up to 3 and `<n>btc` with up to 11 decimals, or `any` if allowed.
+pub fn parse_amount(
+    v: &serde_json::Value,
+    name: &str,
+    allow_any: bool,
+) -> Result<AmountOrAny, Error> {
+    let invalid = || invalid_params(format!("invalid amount for {}: {}", name, v));
+    let msat = match v {
+        serde_json::Value::Number(n) => n.as_u64().ok_or_else(invalid)?,
+        serde_json::Value::String(s) if s == "any" && allow_any => return Ok(AmountOrAny::Any),
+        serde_json::Value::String(s) => {
+            if let Some(msat) = s.strip_suffix("msat") {
+                msat.parse::<u64>().map_err(|_| invalid())?
+            } else if let Some(sat) = s.strip_suffix("sat") {
+                decimal_msat(sat, 3).ok_or_else(invalid)?
+            } else if let Some(btc) = s.strip_suffix("btc") {
+                decimal_msat(btc, 11).ok_or_else(invalid)?
+            } else {
+                s.parse::<u64>().map_err(|_| invalid())?
+            }
+        }
+        _ => return Err(invalid()),
+    };
+    if msat == 0 {
+        return Err(invalid_params(format!("{} must be positive", name)));
     }
+    Ok(AmountOrAny::Amount(Amount::from_msat(msat)))
 }
 
-pub fn make_rpc_path(plugin: &Plugin<PluginState>) -> PathBuf {
-    Path::new(&plugin.configuration().lightning_dir).join(plugin.configuration().rpc_file)
+// `decimals` is how many msat digits the unit has after the point
+fn decimal_msat(s: &str, decimals: u32) -> Option<u64> {
+    let (whole, frac) = s.split_once('.').unwrap_or((s, ""));
+    if whole.is_empty() && frac.is_empty()
+        || frac.len() > decimals as usize
+        || !whole
+            .chars()
+            .chain(frac.chars())
+            .all(|c| c.is_ascii_digit())
+    {
+        return None;
+    }
+    let whole = if whole.is_empty() {
+        0
+    } else {
+        whole.parse::<u64>().ok()?
+    };
+    let frac = if frac.is_empty() {
+        0
+    } else {
+        frac.parse::<u64>().ok()? * 10u64.pow(decimals - frac.len() as u32)
+    };
+    whole.checked_mul(10u64.pow(decimals))?.checked_add(frac)
+}
+
+fn amount_value(amount: AmountOrAny) -> u64 {
+    match amount {
+        AmountOrAny::Amount(a) => a.msat(),
+        AmountOrAny::Any => 0,
+    }
+}
+
+/// Read an msat amount that lightningd sends either as integer or as `"1000msat"` string.
+pub fn msat_from_value(v: &serde_json::Value) -> Option<u64> {
+    match v {
+        serde_json::Value::Number(n) => n.as_u64(),
+        serde_json::Value::String(s) => s.trim_end_matches("msat").parse::<u64>().ok(),
+        _ => None,
+    }
 }
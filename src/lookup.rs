@@ -0,0 +1,181 @@
+use std::{
+    collections::HashMap,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Error;
+use cln_plugin::Plugin;
+use cln_rpc::model::ListinvoicesInvoices;
+use serde_json::json;
+
+use crate::{
+    config::PluginState,
+    error::{rpc_error, HodlError},
+    listinvoices,
+    params::{invalid_params, HodlRefRequest, ListRequest, Params},
+    record::{get_record, list_records, resolve_ref, HodlRecord},
+    ExternalInvoice, Hodlstate,
+};
+
+/// What we report about the invoice behind a hold, wherever it is stored.
+#[derive(Debug, Clone)]
+pub struct InvoiceInfo {
+    pub description: Option<String>,
+    pub bolt11: Option<String>,
+    pub amount_msat: Option<u64>,
+    pub status: Option<String>,
+    pub expires_at: u64,
+}
+impl InvoiceInfo {
+    fn from_invoice(inv: &ListinvoicesInvoices) -> InvoiceInfo {
+        InvoiceInfo {
+            description: inv.description.clone(),
+            bolt11: inv.bolt11.clone(),
+            amount_msat: inv.amount_msat.map(|a| a.msat()),
+            status: Some(format!("{:?}", inv.status).to_lowercase()),
+            expires_at: inv.expires_at,
+        }
+    }
+    fn from_external(inv: &ExternalInvoice) -> InvoiceInfo {
+        InvoiceInfo {
+            description: None,
+            bolt11: Some(inv.bolt11.clone()),
+            amount_msat: inv.amount_msat,
+            status: None,
+            expires_at: inv.expires_at,
+        }
+    }
+}
+
+pub async fn hodlvoicelookup(
+    plugin: Plugin<PluginState>,
+    args: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    lookup_hodlvoice(plugin.state(), args)
+        .await
+        .map_err(rpc_error)
+}
+
+/// The `hodlvoice-lookup` rpc method without the plugin.
+pub async fn lookup_hodlvoice(
+    state: &PluginState,
+    args: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    let rpc = state.rpc.as_ref();
+    let hodl_ref = match HodlRefRequest::refs(args)? {
+        (mut hodl_refs, false) => hodl_refs.remove(0),
+        (_, true) => {
+            return Err(invalid_params(
+                "Please provide exactly one `payment_hash` or `label`",
+            ))
+        }
+    };
+    let pay_hash = resolve_ref(rpc, &hodl_ref).await?;
+
+    let record = get_record(rpc, &pay_hash)
+        .await?
+        .ok_or(HodlError::UnknownHodlvoice(pay_hash.clone()))?;
+
+    let invoice = match &record.external {
+        Some(external) => Some(InvoiceInfo::from_external(external)),
+        None => listinvoices(rpc, None, Some(pay_hash.clone()))
+            .await?
+            .invoices
+            .first()
+            .map(InvoiceInfo::from_invoice),
+    };
+
+    Ok(hodl_summary(state, &record, invoice.as_ref()))
+}
+
+pub async fn hodlvoicelist(
+    plugin: Plugin<PluginState>,
+    args: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    list_hodlvoices(plugin.state(), args)
+        .await
+        .map_err(rpc_error)
+}
+
+/// The `hodlvoice-list` rpc method without the plugin.
+pub async fn list_hodlvoices(
+    state: &PluginState,
+    args: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    let rpc = state.rpc.as_ref();
+    let req = ListRequest::parse(args)?;
+    let state_filter = match &req.state {
+        Some(st) => Some(Hodlstate::from_str(st).ok_or(invalid_params(format!(
+            "invalid `state`: unknown state {}",
+            st
+        )))?),
+        None => None,
+    };
+
+    let invoices: HashMap<String, InvoiceInfo> = listinvoices(rpc, None, None)
+        .await?
+        .invoices
+        .iter()
+        .map(|inv| (inv.payment_hash.to_string(), InvoiceInfo::from_invoice(inv)))
+        .collect();
+    let mut hodlvoices = Vec::new();
+    for record in list_records(rpc).await? {
+        if let Some(st) = &state_filter {
+            if st != &state.effective_hodlstate(&record.payment_hash, record.state.clone()) {
+                continue;
+            }
+        }
+        if req.label.is_some() && req.label != record.label {
+            continue;
+        }
+        if req.created_after.map_or(false, |ca| record.created_at < ca) {
+            continue;
+        }
+        if req
+            .created_before
+            .map_or(false, |cb| record.created_at >= cb)
+        {
+            continue;
+        }
+        let invoice = match &record.external {
+            Some(external) => Some(InvoiceInfo::from_external(external)),
+            None => invoices.get(&record.payment_hash).cloned(),
+        };
+        hodlvoices.push(hodl_summary(state, &record, invoice.as_ref()));
+    }
+
+    Ok(json!({ "hodlvoices": hodlvoices }))
+}
+
+fn hodl_summary(
+    state: &PluginState,
+    record: &HodlRecord,
+    invoice: Option<&InvoiceInfo>,
+) -> serde_json::Value {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let pay_hash = &record.payment_hash;
+    let htlcs = state.held_htlcs(pay_hash);
+
+    json!({
+        "payment_hash": pay_hash,
+        "state": state.effective_hodlstate(pay_hash, record.state.clone()).to_string(),
+        "label": record.label,
+        "description": invoice.and_then(|i| i.description.clone()),
+        "bolt11": invoice.and_then(|i| i.bolt11.clone()),
+        "amount_msat": invoice.and_then(|i| i.amount_msat),
+        "status": invoice.and_then(|i| i.status.clone()),
+        "created_at": record.created_at,
+        "expires_at": invoice.map(|i| i.expires_at),
+        "policy": record.policy,
+        "history": record.history,
+        "held_msat": state.held_msat(pay_hash),
+        "blockheight": *state.blockheight.lock(),
+        "deadline_blockheight": htlcs.iter().map(|h| h.deadline_blockheight).min(),
+        "held_seconds": htlcs.iter().map(|h| h.held_since).min().map(|s| now.saturating_sub(s)),
+        "hold_until": htlcs.iter().map(|h| h.hold_until).min(),
+        "htlcs": htlcs,
+    })
+}
@@ -1,13 +1,22 @@
 use anyhow::anyhow;
-use cln_plugin::Builder;
+use cln_plugin::{messages::NotificationTopic, Builder};
 use hodlvoice::{
-    config::{read_config, PluginState},
-    hodlvoiceaccept, hodlvoiceadd, hodlvoicereject,
-    hooks::block_added,
-    hooks::htlc_handler,
+    callback::start_callbacks,
+    config::{read_config, Config, PluginState},
+    events::{
+        forward_notifications, hodlvoicewait, hodlvoicewaitany, load_event_index,
+        NOTIFICATION_TOPICS,
+    },
+    gc::hodlvoicegc,
+    hodlvoiceaccept, hodlvoiceadd, hodlvoicecancel, hodlvoicereject, hodlvoicesettle,
+    hooks::{block_added, htlc_handler, init_blockheight, invoice_payment},
+    lookup::{hodlvoicelist, hodlvoicelookup},
+    record::{migrate_records, reconcile_records},
+    rpc::RpcClient,
     PLUGIN_NAME,
 };
-use log::info;
+use log::{info, warn};
+use std::{path::Path, sync::Arc};
 use tokio::{self};
 #[cfg(all(not(windows), not(target_env = "musl")))]
 #[global_allocator]
@@ -16,9 +25,16 @@ static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
     std::env::set_var("CLN_PLUGIN_LOG", "trace");
-    let state = PluginState::new();
+    let state;
     let confplugin;
-    match Builder::new(tokio::io::stdin(), tokio::io::stdout())
+    let mut builder = Builder::new(tokio::io::stdin(), tokio::io::stdout());
+    for option in Config::options() {
+        builder = builder.option(option);
+    }
+    for topic in NOTIFICATION_TOPICS {
+        builder = builder.notification(NotificationTopic::new(topic));
+    }
+    match builder
         .rpcmethod(
             &(PLUGIN_NAME.to_string() + "-add"),
             "add hold-invoice",
@@ -34,22 +50,75 @@ async fn main() -> Result<(), anyhow::Error> {
             "reject hold-invoice",
             hodlvoicereject,
         )
+        .rpcmethod(
+            &(PLUGIN_NAME.to_string() + "-cancel"),
+            "reject hold-invoice and delete its invoice",
+            hodlvoicecancel,
+        )
+        .rpcmethod(
+            &(PLUGIN_NAME.to_string() + "-settle"),
+            "settle hold-invoice with preimage",
+            hodlvoicesettle,
+        )
+        .rpcmethod(
+            &(PLUGIN_NAME.to_string() + "-list"),
+            "list hold-invoices",
+            hodlvoicelist,
+        )
+        .rpcmethod(
+            &(PLUGIN_NAME.to_string() + "-lookup"),
+            "lookup hold-invoice",
+            hodlvoicelookup,
+        )
+        .rpcmethod(
+            &(PLUGIN_NAME.to_string() + "-wait"),
+            "wait for the next state change of a hold-invoice",
+            hodlvoicewait,
+        )
+        .rpcmethod(
+            &(PLUGIN_NAME.to_string() + "-waitany"),
+            "wait for the next state change of any hold-invoice",
+            hodlvoicewaitany,
+        )
+        .rpcmethod(
+            &(PLUGIN_NAME.to_string() + "-gc"),
+            "delete finished hold-invoices older than the retention period",
+            hodlvoicegc,
+        )
         .hook("htlc_accepted", htlc_handler)
         .subscribe("block_added", block_added)
+        .subscribe("invoice_payment", invoice_payment)
         .configure()
         .await?
     {
         Some(plugin) => {
+            let rpc_path = Path::new(&plugin.configuration().lightning_dir)
+                .join(plugin.configuration().rpc_file);
+            state = PluginState::new(Arc::new(RpcClient::new(rpc_path)));
             info!("read config");
             match read_config(&plugin, state.clone()).await {
                 Ok(()) => &(),
                 Err(e) => return plugin.disable(format!("{}", e).as_str()).await,
             };
+            if let Err(e) = migrate_records(state.rpc.as_ref()).await {
+                return plugin.disable(format!("{}", e).as_str()).await;
+            }
+            if let Err(e) = load_event_index(&state).await {
+                return plugin.disable(format!("{}", e).as_str()).await;
+            }
+            if let Err(e) = init_blockheight(&state).await {
+                return plugin.disable(format!("{}", e).as_str()).await;
+            }
             confplugin = plugin;
         }
         None => return Err(anyhow!("Error configuring the plugin!")),
     };
     if let Ok(plugin) = confplugin.start(state).await {
+        forward_notifications(&plugin);
+        start_callbacks(plugin.state());
+        if let Err(e) = reconcile_records(plugin.state()).await {
+            warn!("Error reconciling hold-invoices: {}", e);
+        }
         plugin.join().await
     } else {
         Err(anyhow!("Error starting the plugin!"))
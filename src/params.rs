@@ -0,0 +1,267 @@
+use anyhow::Error;
+use serde::{
+    de::{self, DeserializeOwned, DeserializeSeed, IntoDeserializer, MapAccess, Visitor},
+    forward_to_deserialize_any, Deserialize, Deserializer,
+};
+
+use crate::{
+    error::HodlError,
+    record::{HodlRef, OnTimeout},
+};
+
+pub fn invalid_params(message: impl ToString) -> Error {
+    HodlError::InvalidParameter(message.to_string()).into()
+}
+
+/// The parameters of an rpc method, given by name (`-k`) or by position.
+pub trait Params: DeserializeOwned {
+    /// Parameter names in the order they are taken by position.
+    const FIELDS: &'static [&'static str];
+
+    fn parse(args: serde_json::Value) -> Result<Self, Error> {
+        let entries: Vec<(String, serde_json::Value)> = match args {
+            serde_json::Value::Object(o) => o.into_iter().collect(),
+            serde_json::Value::Array(a) => {
+                if a.len() > Self::FIELDS.len() {
+                    return Err(invalid_params(format!(
+                        "too many parameters: got {}, expected at most {} ({})",
+                        a.len(),
+                        Self::FIELDS.len(),
+                        Self::FIELDS.join(", ")
+                    )));
+                }
+                Self::FIELDS.iter().map(|f| f.to_string()).zip(a).collect()
+            }
+            other => return Err(invalid_params(format!("invalid parameters: {}", other))),
+        };
+        Self::deserialize(ParamsDeserializer {
+            // lightning-cli sends skipped positional parameters as null
+            entries: entries
+                .into_iter()
+                .filter(|(_, v)| !v.is_null())
+                .collect::<Vec<_>>()
+                .into_iter(),
+            value: None,
+        })
+        .map_err(invalid_params)
+    }
+}
+
+// feeds the parameters to the derived `Deserialize` and names the one that failed
+struct ParamsDeserializer {
+    entries: std::vec::IntoIter<(String, serde_json::Value)>,
+    value: Option<(String, serde_json::Value)>,
+}
+
+impl<'de> Deserializer<'de> for ParamsDeserializer {
+    type Error = serde_json::Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_map(self)
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map struct enum identifier ignored_any
+    }
+}
+
+impl<'de> MapAccess<'de> for ParamsDeserializer {
+    type Error = serde_json::Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, Self::Error> {
+        match self.entries.next() {
+            Some((key, value)) => {
+                let k = seed.deserialize(key.clone().into_deserializer())?;
+                self.value = Some((key, value));
+                Ok(Some(k))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(
+        &mut self,
+        seed: V,
+    ) -> Result<V::Value, Self::Error> {
+        let (key, value) = self
+            .value
+            .take()
+            .ok_or_else(|| de::Error::custom("parameter without a name"))?;
+        seed.deserialize(value)
+            .map_err(|e| de::Error::custom(format!("invalid `{}`: {}", key, e)))
+    }
+}
+
+/// A single string or a non-empty list of them.
+#[derive(Debug, Clone)]
+pub enum OneOrMany {
+    One(String),
+    Many(Vec<String>),
+}
+impl<'de> Deserialize<'de> for OneOrMany {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let expected = "expected a string or a non-empty list of strings";
+        match serde_json::Value::deserialize(deserializer)? {
+            serde_json::Value::String(s) => Ok(OneOrMany::One(s)),
+            serde_json::Value::Array(a) if !a.is_empty() => a
+                .into_iter()
+                .map(|v| match v {
+                    serde_json::Value::String(s) => Ok(s),
+                    other => Err(de::Error::custom(format!("{}, got {}", expected, other))),
+                })
+                .collect::<Result<Vec<String>, D::Error>>()
+                .map(OneOrMany::Many),
+            other => Err(de::Error::custom(format!("{}, got {}", expected, other))),
+        }
+    }
+}
+impl OneOrMany {
+    fn into_refs(self, make: fn(String) -> HodlRef) -> (Vec<HodlRef>, bool) {
+        match self {
+            OneOrMany::One(s) => (vec![make(s)], false),
+            OneOrMany::Many(list) => (list.into_iter().map(make).collect(), true),
+        }
+    }
+}
+
+/// `hodlvoice-add`, positional parameters are in the order of lightningd's `invoice`.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AddRequest {
+    pub amount_msat: serde_json::Value,
+    pub label: String,
+    pub description: String,
+    pub expiry: Option<u64>,
+    pub fallbacks: Option<Vec<String>>,
+    pub preimage: Option<String>,
+    pub exposeprivatechannels: Option<bool>,
+    // only here to keep the positions of `invoice`, it is set from the plugin options
+    pub cltv: Option<u32>,
+    pub deschashonly: Option<bool>,
+    pub payment_hash: Option<String>,
+    pub hold_blocks: Option<u32>,
+    pub on_timeout: Option<OnTimeout>,
+    pub max_hold_seconds: Option<u64>,
+    pub min_msat: Option<serde_json::Value>,
+    pub max_msat: Option<serde_json::Value>,
+}
+impl Params for AddRequest {
+    const FIELDS: &'static [&'static str] = &[
+        "amount_msat",
+        "label",
+        "description",
+        "expiry",
+        "fallbacks",
+        "preimage",
+        "exposeprivatechannels",
+        "cltv",
+        "deschashonly",
+        "payment_hash",
+        "hold_blocks",
+        "on_timeout",
+        "max_hold_seconds",
+        "min_msat",
+        "max_msat",
+    ];
+}
+
+/// `hodlvoice-accept`, `-reject`, `-cancel` and `-lookup`: hold-invoices by payment hash or label.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HodlRefRequest {
+    pub payment_hash: Option<OneOrMany>,
+    pub label: Option<OneOrMany>,
+}
+impl Params for HodlRefRequest {
+    // a positional parameter can be either, see `refs`
+    const FIELDS: &'static [&'static str] = &[];
+}
+impl HodlRefRequest {
+    /// The referenced hold-invoices and whether they were given as a list.
+    pub fn refs(args: serde_json::Value) -> Result<(Vec<HodlRef>, bool), Error> {
+        let missing = || invalid_params("Please provide exactly one `payment_hash` or `label`");
+        match args {
+            serde_json::Value::Array(mut a) => {
+                if a.len() != 1 {
+                    return Err(missing());
+                }
+                let ids = OneOrMany::deserialize(a.remove(0)).map_err(|e| {
+                    invalid_params(format!("invalid `payment_hash` or `label`: {}", e))
+                })?;
+                Ok(ids.into_refs(HodlRef::Any))
+            }
+            args => match HodlRefRequest::parse(args)? {
+                HodlRefRequest {
+                    payment_hash: Some(ph),
+                    label: None,
+                } => Ok(ph.into_refs(HodlRef::PaymentHash)),
+                HodlRefRequest {
+                    payment_hash: None,
+                    label: Some(label),
+                } => Ok(label.into_refs(HodlRef::Label)),
+                _ => Err(missing()),
+            },
+        }
+    }
+}
+
+/// `hodlvoice-settle`
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SettleRequest {
+    pub payment_hash: String,
+    pub preimage: String,
+}
+impl Params for SettleRequest {
+    const FIELDS: &'static [&'static str] = &["payment_hash", "preimage"];
+}
+
+/// `hodlvoice-list`
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ListRequest {
+    pub state: Option<String>,
+    pub label: Option<String>,
+    pub created_after: Option<u64>,
+    pub created_before: Option<u64>,
+}
+impl Params for ListRequest {
+    const FIELDS: &'static [&'static str] = &["state", "label", "created_after", "created_before"];
+}
+
+/// `hodlvoice-wait`
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WaitRequest {
+    pub payment_hash: String,
+    pub timeout: Option<u64>,
+}
+impl Params for WaitRequest {
+    const FIELDS: &'static [&'static str] = &["payment_hash", "timeout"];
+}
+
+/// `hodlvoice-waitany`
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WaitanyRequest {
+    pub lastindex: Option<u64>,
+    pub timeout: Option<u64>,
+}
+impl Params for WaitanyRequest {
+    const FIELDS: &'static [&'static str] = &["lastindex", "timeout"];
+}
+
+/// `hodlvoice-gc`
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GcRequest {
+    pub dry_run: Option<bool>,
+}
+impl Params for GcRequest {
+    const FIELDS: &'static [&'static str] = &["dry_run"];
+}
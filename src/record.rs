@@ -0,0 +1,554 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Error};
+use cln_rpc::model::{DatastoreMode, ListinvoicesInvoicesStatus};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    bolt11,
+    config::PluginState,
+    datastore,
+    error::{refused_by, HodlError},
+    events::{emit_event, notify, NOTIFICATION_INCONSISTENCY},
+    listdatastore, listinvoices,
+    rpc::Rpc,
+    ExternalInvoice, Hodlstate, PLUGIN_NAME,
+};
+
+pub const RECORD_VERSION: u32 = 1;
+
+// what invoices were created with before the hold window was configurable
+const LEGACY_SAFETY_BLOCKS: u32 = 200;
+// how often we re-read a record that changed while we were updating it
+const TRANSITION_ATTEMPTS: usize = 5;
+
+/// What we store under `["hodlvoice", payment_hash]`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HodlRecord {
+    pub version: u32,
+    pub payment_hash: String,
+    pub state: Hodlstate,
+    pub created_at: u64,
+    pub label: Option<String>,
+    #[serde(default)]
+    pub policy: Policy,
+    #[serde(default)]
+    pub history: Vec<Transition>,
+    #[serde(default)]
+    pub htlcs: HtlcSummary,
+    // only for invoices created from a payment_hash
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub external: Option<ExternalInvoice>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub preimage: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Policy {
+    #[serde(default)]
+    pub safety_blocks: u32,
+    #[serde(default)]
+    pub hold_blocks: u32,
+    #[serde(default)]
+    pub on_timeout: OnTimeout,
+    // falls back to the plugin option if not set
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub max_hold_seconds: Option<u64>,
+    // bounds for the total of `any` amount invoices
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub min_msat: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub max_msat: Option<u64>,
+}
+impl Default for Policy {
+    fn default() -> Policy {
+        Policy {
+            safety_blocks: LEGACY_SAFETY_BLOCKS,
+            hold_blocks: 0,
+            on_timeout: OnTimeout::Reject,
+            max_hold_seconds: None,
+            min_msat: None,
+            max_msat: None,
+        }
+    }
+}
+
+/// What to do with the held htlcs if no decision was made before they time out.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OnTimeout {
+    #[default]
+    Reject,
+    Accept,
+}
+impl OnTimeout {
+    pub fn from_str(s: &str) -> Option<OnTimeout> {
+        match s.to_lowercase().as_str() {
+            "reject" => Some(OnTimeout::Reject),
+            "accept" => Some(OnTimeout::Accept),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Transition {
+    pub state: Hodlstate,
+    pub at: u64,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct HtlcSummary {
+    pub count: usize,
+    pub held_msat: u64,
+}
+
+impl HodlRecord {
+    pub fn new(
+        payment_hash: String,
+        label: Option<String>,
+        created_at: u64,
+        policy: Policy,
+    ) -> HodlRecord {
+        HodlRecord {
+            version: RECORD_VERSION,
+            payment_hash,
+            state: Hodlstate::Open,
+            created_at,
+            label,
+            policy,
+            history: vec![Transition {
+                state: Hodlstate::Open,
+                at: created_at,
+                reason: None,
+            }],
+            htlcs: HtlcSummary::default(),
+            external: None,
+            preimage: None,
+        }
+    }
+
+    pub fn transition(&mut self, state: Hodlstate, reason: Option<String>) {
+        self.history.push(Transition {
+            state: state.clone(),
+            at: now(),
+            reason,
+        });
+        self.state = state;
+    }
+
+    pub fn from_str(s: &str) -> Result<HodlRecord, Error> {
+        let record: HodlRecord =
+            serde_json::from_str(s).map_err(|e| anyhow!("invalid hodlvoice record: {}", e))?;
+        if record.version > RECORD_VERSION {
+            return Err(anyhow!(
+                "hodlvoice record version {} is newer than supported version {}",
+                record.version,
+                RECORD_VERSION
+            ));
+        }
+        Ok(record)
+    }
+}
+
+pub async fn get_record(rpc: &dyn Rpc, pay_hash: &str) -> Result<Option<HodlRecord>, Error> {
+    Ok(get_record_generation(rpc, pay_hash)
+        .await?
+        .map(|(record, _)| record))
+}
+
+/// The record together with its datastore generation, to update it atomically.
+pub async fn get_record_generation(
+    rpc: &dyn Rpc,
+    pay_hash: &str,
+) -> Result<Option<(HodlRecord, u64)>, Error> {
+    let resp = listdatastore(
+        rpc,
+        Some(vec![PLUGIN_NAME.to_string(), pay_hash.to_string()]),
+    )
+    .await?;
+    match resp.datastore.first() {
+        Some(entry) => match &entry.string {
+            Some(s) => Ok(Some((
+                HodlRecord::from_str(s)?,
+                entry.generation.unwrap_or(0),
+            ))),
+            None => Ok(None),
+        },
+        None => Ok(None),
+    }
+}
+
+/// Write `record`, only if it is still at `generation` if one is given.
+pub async fn store_record(
+    rpc: &dyn Rpc,
+    record: &HodlRecord,
+    mode: DatastoreMode,
+    generation: Option<u64>,
+) -> Result<(), Error> {
+    datastore(
+        rpc,
+        vec![PLUGIN_NAME.to_string(), record.payment_hash.clone()],
+        Some(serde_json::to_string(record)?),
+        None,
+        Some(mode),
+        generation,
+    )
+    .await?;
+    Ok(())
+}
+
+/// Persist a new state for `pay_hash`, then wake the held htlcs and emit the event.
+/// Fails with `HodlError::IllegalTransition` if the stored state can't change to `hodlstate`.
+pub async fn transition_record(
+    state: &PluginState,
+    pay_hash: &str,
+    hodlstate: Hodlstate,
+    reason: Option<String>,
+    update: impl Fn(&mut HodlRecord) -> Result<(), Error>,
+) -> Result<HodlRecord, Error> {
+    compare_and_transition(state, pay_hash, hodlstate, reason, true, update).await
+}
+
+/// Like `transition_record` but without checking the state machine, for when
+/// lightningd already decided for us.
+pub async fn force_transition_record(
+    state: &PluginState,
+    pay_hash: &str,
+    hodlstate: Hodlstate,
+    reason: Option<String>,
+) -> Result<HodlRecord, Error> {
+    compare_and_transition(state, pay_hash, hodlstate, reason, false, |_| Ok(())).await
+}
+
+// read the record, change it and write it back if nobody else wrote it in the meantime
+async fn compare_and_transition(
+    state: &PluginState,
+    pay_hash: &str,
+    hodlstate: Hodlstate,
+    reason: Option<String>,
+    check: bool,
+    update: impl Fn(&mut HodlRecord) -> Result<(), Error>,
+) -> Result<HodlRecord, Error> {
+    let rpc = state.rpc.as_ref();
+    let mut failed: Option<(u64, Error)> = None;
+    for _ in 0..TRANSITION_ATTEMPTS {
+        let (mut record, generation) = get_record_generation(rpc, pay_hash)
+            .await?
+            .ok_or(HodlError::UnknownHodlvoice(pay_hash.to_string()))?;
+        if let Some((failed_generation, e)) = failed.take() {
+            // the write failed for another reason than a concurrent update
+            if failed_generation == generation {
+                return Err(e);
+            }
+        }
+        if check && !record.state.can_transition_to(&hodlstate) {
+            return Err(match record.state {
+                Hodlstate::Expired => HodlError::InvoiceExpired {
+                    payment_hash: pay_hash.to_string(),
+                    requested: hodlstate,
+                },
+                current => HodlError::IllegalTransition {
+                    payment_hash: pay_hash.to_string(),
+                    current,
+                    requested: hodlstate,
+                },
+            }
+            .into());
+        }
+        update(&mut record)?;
+        record.transition(hodlstate.clone(), reason.clone());
+        record.htlcs = HtlcSummary {
+            count: state.held_htlcs(pay_hash).len(),
+            held_msat: state.held_msat(pay_hash),
+        };
+        match store_record(rpc, &record, DatastoreMode::MUST_REPLACE, Some(generation)).await {
+            Ok(()) => {
+                state.update_hodlstate(pay_hash, hodlstate.clone());
+                // the transition is stored already, the caller must not see it as failed
+                if let Err(e) = emit_event(state, &record).await {
+                    warn!("Error emitting the event for {}: {}", pay_hash, e);
+                }
+                return Ok(record);
+            }
+            Err(e) => {
+                debug!("record for {} changed while updating it: {}", pay_hash, e);
+                failed = Some((generation, e));
+            }
+        }
+    }
+    Err(HodlError::ConcurrentUpdate {
+        payment_hash: pay_hash.to_string(),
+        attempts: TRANSITION_ATTEMPTS,
+    }
+    .into())
+}
+
+/// Convert the plain state strings of older versions into records.
+pub async fn migrate_records(rpc: &dyn Rpc) -> Result<(), Error> {
+    let entries = listdatastore(rpc, Some(vec![PLUGIN_NAME.to_string()]))
+        .await?
+        .datastore;
+    for entry in entries {
+        let (pay_hash, state) = match (entry.key.get(1), entry.string.as_ref()) {
+            (Some(ph), Some(s)) if entry.key.len() == 2 => (ph, s),
+            _ => continue,
+        };
+        let hodlstate = match Hodlstate::from_str(state) {
+            Some(st) => st,
+            // already a record, or the event index
+            None => continue,
+        };
+
+        let invoice = listinvoices(rpc, None, Some(pay_hash.clone()))
+            .await?
+            .invoices
+            .into_iter()
+            .next();
+        let label = match &invoice {
+            Some(inv) => Some(inv.label.clone()),
+            None => {
+                warn!("no invoice found for hodlvoice entry {}", pay_hash);
+                None
+            }
+        };
+        let created_at = invoice
+            .and_then(|inv| inv.bolt11)
+            .and_then(|b| bolt11::timestamp(&b))
+            .unwrap_or_else(now);
+
+        let mut record = HodlRecord::new(pay_hash.clone(), label, created_at, Policy::default());
+        if hodlstate != Hodlstate::Open {
+            record.transition(hodlstate, Some("migrated".to_string()));
+        }
+
+        store_record(rpc, &record, DatastoreMode::MUST_REPLACE, entry.generation).await?;
+        info!("migrated hodlvoice entry {} to record", pay_hash);
+    }
+    Ok(())
+}
+
+/// Bring the stored states up to date with lightningd after a restart.
+pub async fn reconcile_records(state: &PluginState) -> Result<(), Error> {
+    let rpc = state.rpc.as_ref();
+    let invoices = listinvoices(rpc, None, None).await?.invoices;
+    let (mut checked, mut paid, mut orphaned) = (0, 0, 0);
+    for record in list_records(rpc).await? {
+        let pay_hash = record.payment_hash.clone();
+        // htlcs replayed by lightningd take care of these themselves
+        if state.holds.lock().contains_key(&pay_hash) {
+            continue;
+        }
+        checked += 1;
+
+        let invoice = invoices
+            .iter()
+            .find(|inv| inv.payment_hash.to_string() == pay_hash);
+        match (invoice, &record.external) {
+            (Some(inv), _) if matches!(inv.status, ListinvoicesInvoicesStatus::PAID) => {
+                if record_payment(state, &record).await? {
+                    paid += 1;
+                }
+            }
+            (Some(_), _) | (None, Some(_)) => (),
+            (None, None) => {
+                orphaned += 1;
+                inconsistency(state, &record, "no invoice found for hold-invoice");
+            }
+        }
+    }
+    let expired = expire_records(state).await?;
+    info!(
+        "reconciled {} hold-invoices: {} expired, {} paid, {} without invoice",
+        checked, expired, paid, orphaned
+    );
+    Ok(())
+}
+
+/// lightningd got paid for `record`, mark it settled. Returns false if it already was.
+pub async fn record_payment(state: &PluginState, record: &HodlRecord) -> Result<bool, Error> {
+    let reason = Some("invoice paid".to_string());
+    match record.state {
+        Hodlstate::Settled => return Ok(false),
+        Hodlstate::Accepted => {
+            match transition_record(
+                state,
+                &record.payment_hash,
+                Hodlstate::Settled,
+                reason,
+                |_| Ok(()),
+            )
+            .await
+            {
+                Ok(_) => (),
+                // settled in the meantime
+                Err(e) if refused_by(&e).is_some() => return Ok(false),
+                Err(e) => return Err(e),
+            }
+        }
+        _ => {
+            inconsistency(state, record, "invoice paid while not accepted");
+            // lightningd already took the money, even if we rejected or expired it
+            force_transition_record(state, &record.payment_hash, Hodlstate::Settled, reason)
+                .await?;
+        }
+    }
+    Ok(true)
+}
+
+/// Mark hold-invoices that can't be paid anymore as expired, called for every block.
+/// Hold-invoices with held htlcs are left to the htlc_accepted hook.
+pub async fn expire_records(state: &PluginState) -> Result<usize, Error> {
+    let rpc = state.rpc.as_ref();
+    let now = now();
+    let mut candidates = Vec::new();
+    for record in list_records(rpc).await? {
+        if record.state.is_final() || state.holds.lock().contains_key(&record.payment_hash) {
+            continue;
+        }
+        match &record.external {
+            Some(external) if external.expires_at <= now => candidates.push(record),
+            Some(_) => (),
+            None => candidates.push(record),
+        }
+    }
+    if candidates.iter().any(|r| r.external.is_none()) {
+        let invoices = listinvoices(rpc, None, None).await?.invoices;
+        candidates.retain(|record| {
+            record.external.is_some()
+                || invoices.iter().any(|inv| {
+                    inv.payment_hash.to_string() == record.payment_hash
+                        && matches!(inv.status, ListinvoicesInvoicesStatus::EXPIRED)
+                })
+        });
+    }
+
+    let mut expired = 0;
+    for record in candidates {
+        match transition_record(
+            state,
+            &record.payment_hash,
+            Hodlstate::Expired,
+            Some("invoice expired".to_string()),
+            |_| Ok(()),
+        )
+        .await
+        {
+            Ok(_) => expired += 1,
+            // changed in the meantime
+            Err(e) if refused_by(&e).is_some() => {
+                debug!("not expiring: {}", e)
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(expired)
+}
+
+/// How an rpc call names a hold-invoice.
+#[derive(Debug, Clone)]
+pub enum HodlRef {
+    PaymentHash(String),
+    Label(String),
+    // positional, a payment_hash if we have a hold-invoice for it, otherwise a label
+    Any(String),
+}
+impl HodlRef {
+    /// The reference as given, to report it back.
+    pub fn to_json(&self) -> serde_json::Value {
+        match self {
+            HodlRef::PaymentHash(ph) => json!({ "payment_hash": ph }),
+            HodlRef::Label(label) => json!({ "label": label }),
+            HodlRef::Any(id) if is_payment_hash(id) => json!({ "payment_hash": id }),
+            HodlRef::Any(id) => json!({ "label": id }),
+        }
+    }
+}
+
+fn is_payment_hash(s: &str) -> bool {
+    s.len() == 64 && s.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Find the payment_hash of the hold-invoice `hodl_ref` names.
+pub async fn resolve_ref(rpc: &dyn Rpc, hodl_ref: &HodlRef) -> Result<String, Error> {
+    match hodl_ref {
+        HodlRef::PaymentHash(ph) => Ok(ph.to_lowercase()),
+        HodlRef::Label(label) => resolve_label(rpc, label)
+            .await?
+            .ok_or(HodlError::UnknownHodlvoice(label.to_string()).into()),
+        HodlRef::Any(id) => {
+            if is_payment_hash(id) && get_record(rpc, &id.to_lowercase()).await?.is_some() {
+                return Ok(id.to_lowercase());
+            }
+            resolve_label(rpc, id)
+                .await?
+                .ok_or(HodlError::UnknownHodlvoice(id.to_string()).into())
+        }
+    }
+}
+
+pub async fn resolve_label(rpc: &dyn Rpc, label: &str) -> Result<Option<String>, Error> {
+    if let Some(inv) = listinvoices(rpc, Some(label.to_string()), None)
+        .await?
+        .invoices
+        .first()
+    {
+        return Ok(Some(inv.payment_hash.to_string()));
+    }
+    external_label(rpc, label).await
+}
+
+/// The payment hash of the invoice created from a payment_hash with this label, these are
+/// only known to us.
+pub async fn external_label(rpc: &dyn Rpc, label: &str) -> Result<Option<String>, Error> {
+    Ok(list_records(rpc)
+        .await?
+        .into_iter()
+        .find(|r| r.external.is_some() && r.label.as_deref() == Some(label))
+        .map(|r| r.payment_hash))
+}
+
+/// All hold-invoice records in the datastore.
+pub async fn list_records(rpc: &dyn Rpc) -> Result<Vec<HodlRecord>, Error> {
+    let mut records = Vec::new();
+    for entry in listdatastore(rpc, Some(vec![PLUGIN_NAME.to_string()]))
+        .await?
+        .datastore
+    {
+        // skip the event index and the callback outbox
+        match (entry.key.get(1), entry.string.as_ref()) {
+            (Some(ph), Some(s)) if entry.key.len() == 2 && ph.len() == 64 => {
+                records.push(HodlRecord::from_str(s)?)
+            }
+            _ => continue,
+        }
+    }
+    Ok(records)
+}
+
+fn inconsistency(state: &PluginState, record: &HodlRecord, issue: &str) {
+    warn!(
+        "{} for payment_hash: {} state: {}",
+        issue, record.payment_hash, record.state
+    );
+    notify(
+        state,
+        NOTIFICATION_INCONSISTENCY,
+        json!({
+            "payment_hash": record.payment_hash,
+            "state": record.state.to_string(),
+            "issue": issue,
+        }),
+    );
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
@@ -0,0 +1,98 @@
+use std::{future::Future, path::PathBuf, pin::Pin, time::Duration};
+
+use cln_rpc::{ClnRpc, Request, Response, RpcError};
+use log::debug;
+use parking_lot::Mutex;
+use tokio::{sync::Semaphore, time};
+
+// connections to lightningd we keep open at most
+const RPC_POOL_SIZE: usize = 4;
+const RPC_TIMEOUT: Duration = Duration::from_secs(60);
+
+pub type RpcFuture<'a> = Pin<Box<dyn Future<Output = Result<Response, RpcError>> + Send + 'a>>;
+
+/// How we talk to lightningd, the tests replace it with an in-memory fake.
+pub trait Rpc: Send + Sync {
+    fn call(&self, request: Request) -> RpcFuture<'_>;
+}
+
+/// Connections to lightningd shared by all rpc methods, hooks and notifications.
+pub struct RpcClient {
+    rpc_path: PathBuf,
+    idle: Mutex<Vec<ClnRpc>>,
+    permits: Semaphore,
+}
+
+impl RpcClient {
+    pub fn new(rpc_path: PathBuf) -> RpcClient {
+        RpcClient {
+            rpc_path,
+            idle: Mutex::new(Vec::new()),
+            permits: Semaphore::new(RPC_POOL_SIZE),
+        }
+    }
+
+    /// Send `request` on an idle connection, opening a new one if needed.
+    async fn send(&self, request: Request) -> Result<Response, RpcError> {
+        let _permit = self.permits.acquire().await.map_err(rpc_error)?;
+
+        let pooled = self.idle.lock().pop();
+        if let Some(mut rpc) = pooled {
+            match self.call_on(&mut rpc, request.clone()).await {
+                // lightningd closed the connection in the meantime, try again on a new one
+                Err(e) if connection_closed(&e) => {
+                    debug!("reconnecting to lightningd: {}", e.message)
+                }
+                result => {
+                    self.release(rpc, &result);
+                    return result;
+                }
+            }
+        }
+
+        let mut rpc = ClnRpc::new(&self.rpc_path).await.map_err(rpc_error)?;
+        let result = self.call_on(&mut rpc, request).await;
+        self.release(rpc, &result);
+        result
+    }
+
+    async fn call_on(&self, rpc: &mut ClnRpc, request: Request) -> Result<Response, RpcError> {
+        match time::timeout(RPC_TIMEOUT, rpc.call(request)).await {
+            Ok(result) => result,
+            // lightningd may still act on it, so it must not be sent again
+            Err(_) => Err(rpc_error(format!(
+                "lightningd did not answer within {}s",
+                RPC_TIMEOUT.as_secs()
+            ))),
+        }
+    }
+
+    // keep the connection unless it broke or timed out
+    fn release(&self, rpc: ClnRpc, result: &Result<Response, RpcError>) {
+        match result {
+            Err(e) if e.code.is_none() => (),
+            _ => self.idle.lock().push(rpc),
+        }
+    }
+}
+
+impl Rpc for RpcClient {
+    fn call(&self, request: Request) -> RpcFuture<'_> {
+        Box::pin(self.send(request))
+    }
+}
+
+// cln-rpc could not write the request, or the connection closed before a response came
+fn connection_closed(e: &RpcError) -> bool {
+    e.code.is_none()
+        && (e.message.starts_with("Error passing request to lightningd")
+            || e.message == "no response from lightningd")
+}
+
+fn rpc_error(e: impl ToString) -> RpcError {
+    RpcError {
+        code: None,
+        message: e.to_string(),
+        data: None,
+    }
+}
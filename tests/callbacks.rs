@@ -0,0 +1,220 @@
+mod common;
+
+use std::{
+    fs,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
+
+use common::{add, plugin_state, wait_until, FakeLightningd};
+use hodlvoice::{
+    accept_hodlvoice,
+    callback::{queue_callback, start_callbacks, HttpUrl},
+    config::PluginState,
+    events::HodlEvent,
+    reject_hodlvoice,
+};
+use serde_json::json;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+    sync::mpsc,
+};
+
+// a scratch directory with an executable `callback` running `script`
+fn callback_dir(script: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!(
+        "hodlvoice-{}",
+        hex::encode(rand::random::<[u8; 8]>())
+    ));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("callback");
+    fs::write(
+        &path,
+        format!("#!/bin/sh\ncd {}\n{}\n", dir.display(), script),
+    )
+    .unwrap();
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+    dir
+}
+
+fn exec_state(lightningd: &std::sync::Arc<FakeLightningd>, dir: &Path) -> PluginState {
+    let state = plugin_state(lightningd);
+    state.config.lock().callback_exec.1 = Some(dir.join("callback").display().to_string());
+    state
+}
+
+fn delivered(dir: &Path) -> Vec<serde_json::Value> {
+    fs::read_to_string(dir.join("events"))
+        .unwrap_or_default()
+        .lines()
+        .map(|l| serde_json::from_str(l).unwrap())
+        .collect()
+}
+
+fn outbox(lightningd: &FakeLightningd) -> usize {
+    lightningd
+        .datastore_keys()
+        .iter()
+        .filter(|k| k.get(1).map(String::as_str) == Some("outbox"))
+        .count()
+}
+
+#[tokio::test]
+async fn exec_gets_events_in_order() {
+    let dir = callback_dir("cat >> events; echo >> events");
+    let lightningd = FakeLightningd::new();
+    let state = exec_state(&lightningd, &dir);
+    start_callbacks(&state);
+
+    let rejected = add(&state, "rejected").await;
+    let accepted = add(&state, "accepted").await;
+    reject_hodlvoice(&state, json!([rejected])).await.unwrap();
+    accept_hodlvoice(&state, json!([accepted])).await.unwrap();
+
+    wait_until(|| delivered(&dir).len() == 2).await;
+    let events = delivered(&dir);
+    assert_eq!(events[0]["payment_hash"], json!(rejected));
+    assert_eq!(events[0]["label"], json!("rejected"));
+    assert_eq!(events[0]["state"], json!("canceled"));
+    assert_eq!(events[1]["payment_hash"], json!(accepted));
+    assert_eq!(events[1]["state"], json!("accepted"));
+    wait_until(|| outbox(&lightningd) == 0).await;
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn failed_delivery_is_retried() {
+    // fails the first time it runs
+    let dir =
+        callback_dir("[ -e tried ] || { touch tried; exit 1; }\ncat >> events; echo >> events");
+    let lightningd = FakeLightningd::new();
+    let state = exec_state(&lightningd, &dir);
+    start_callbacks(&state);
+
+    let ph = add(&state, "retry").await;
+    reject_hodlvoice(&state, json!([ph])).await.unwrap();
+
+    wait_until(|| delivered(&dir).len() == 1).await;
+    assert!(dir.join("tried").exists());
+    assert_eq!(delivered(&dir)[0]["state"], json!("canceled"));
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn outbox_survives_a_restart() {
+    let dir = callback_dir("cat >> events; echo >> events");
+    let lightningd = FakeLightningd::new();
+    // stopped before it could deliver anything
+    let state = exec_state(&lightningd, &dir);
+    let ph = add(&state, "restart").await;
+    reject_hodlvoice(&state, json!([ph])).await.unwrap();
+    assert_eq!(outbox(&lightningd), 1);
+
+    let restarted = exec_state(&lightningd, &dir);
+    start_callbacks(&restarted);
+    wait_until(|| delivered(&dir).len() == 1).await;
+    assert_eq!(delivered(&dir)[0]["payment_hash"], json!(ph));
+    wait_until(|| outbox(&lightningd) == 0).await;
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn repeated_index_is_queued_twice() {
+    let dir = callback_dir("cat >> events; echo >> events");
+    let lightningd = FakeLightningd::new();
+    let state = exec_state(&lightningd, &dir);
+    let event = HodlEvent {
+        index: 1,
+        payment_hash: "00".repeat(32),
+        label: None,
+        state: "canceled".to_string(),
+        timestamp: 0,
+    };
+    queue_callback(&state, &event).await.unwrap();
+    queue_callback(&state, &event).await.unwrap();
+    assert_eq!(outbox(&lightningd), 2);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn no_outbox_without_callback() {
+    let lightningd = FakeLightningd::new();
+    let state = plugin_state(&lightningd);
+    let ph = add(&state, "quiet").await;
+    reject_hodlvoice(&state, json!([ph])).await.unwrap();
+    assert_eq!(outbox(&lightningd), 0);
+}
+
+#[tokio::test]
+async fn url_gets_events_posted() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        // the first request is answered with an error and has to be retried
+        for status in ["500 Internal Server Error", "200 OK"] {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 1024];
+            loop {
+                let n = socket.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..n]);
+                let text = String::from_utf8_lossy(&request).to_string();
+                if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                    let length = head
+                        .lines()
+                        .find_map(|l| l.strip_prefix("Content-Length: "))
+                        .unwrap()
+                        .parse::<usize>()
+                        .unwrap();
+                    if body.len() >= length {
+                        break;
+                    }
+                }
+            }
+            socket
+                .write_all(format!("HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status).as_bytes())
+                .await
+                .unwrap();
+            socket.shutdown().await.unwrap();
+            tx.send(String::from_utf8(request).unwrap()).unwrap();
+        }
+    });
+
+    let lightningd = FakeLightningd::new();
+    let state = plugin_state(&lightningd);
+    state.config.lock().callback_url.1 =
+        Some(HttpUrl::parse(&format!("http://127.0.0.1:{}/hodl", port)).unwrap());
+    start_callbacks(&state);
+    let ph = add(&state, "posted").await;
+    reject_hodlvoice(&state, json!([ph])).await.unwrap();
+
+    let first = rx.recv().await.unwrap();
+    let second = rx.recv().await.unwrap();
+    assert_eq!(first, second);
+    assert!(second.starts_with("POST /hodl HTTP/1.1\r\n"), "{}", second);
+    let body: serde_json::Value =
+        serde_json::from_str(second.split_once("\r\n\r\n").unwrap().1).unwrap();
+    assert_eq!(body["payment_hash"], json!(ph));
+    assert_eq!(body["state"], json!("canceled"));
+    wait_until(|| outbox(&lightningd) == 0).await;
+}
+
+#[test]
+fn http_urls() {
+    let url = HttpUrl::parse("http://localhost:8080/hooks/hodl").unwrap();
+    assert_eq!(
+        (url.host.as_str(), url.port, url.path.as_str()),
+        ("localhost", 8080, "/hooks/hodl")
+    );
+    let url = HttpUrl::parse("http://[::1]:9000").unwrap();
+    assert_eq!(
+        (url.host.as_str(), url.port, url.path.as_str()),
+        ("::1", 9000, "/")
+    );
+    let url = HttpUrl::parse("http://127.0.0.1/").unwrap();
+    assert_eq!(url.port, 80);
+    assert!(HttpUrl::parse("https://localhost/").is_err());
+    assert!(HttpUrl::parse("http://localhost:port/").is_err());
+}
@@ -0,0 +1,383 @@
+#![allow(dead_code)]
+
+use std::{
+    collections::BTreeMap,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use bitcoin::hashes::{sha256, Hash};
+use cln_rpc::{
+    model::{DatastoreMode, ListinvoicesRequest},
+    primitives::AmountOrAny,
+    Request, Response, RpcError,
+};
+use hodlvoice::{
+    add_hodlvoice,
+    config::PluginState,
+    hooks::handle_block,
+    record::get_record,
+    rpc::{Rpc, RpcFuture},
+    Hodlstate,
+};
+use parking_lot::Mutex;
+use serde_json::json;
+
+pub const BLOCKHEIGHT: u64 = 100;
+
+/// An in-memory lightningd with invoices, a datastore and a block height.
+pub struct FakeLightningd {
+    node: Mutex<Node>,
+}
+
+#[derive(Default)]
+struct Node {
+    invoices: Vec<Invoice>,
+    // key -> (string, generation)
+    datastore: BTreeMap<Vec<String>, (String, u64)>,
+    blockheight: u64,
+}
+
+#[derive(Clone)]
+struct Invoice {
+    label: String,
+    description: String,
+    payment_hash: String,
+    preimage: String,
+    amount_msat: Option<u64>,
+    expires_at: u64,
+    status: &'static str,
+}
+
+impl FakeLightningd {
+    pub fn new() -> Arc<FakeLightningd> {
+        Arc::new(FakeLightningd {
+            node: Mutex::new(Node {
+                blockheight: BLOCKHEIGHT,
+                ..Default::default()
+            }),
+        })
+    }
+
+    /// Let the invoice expire `seconds` from now.
+    pub fn set_expiry(&self, payment_hash: &str, seconds: u64) {
+        let mut node = self.node.lock();
+        for inv in node.invoices.iter_mut() {
+            if inv.payment_hash == payment_hash {
+                inv.expires_at = now() + seconds;
+            }
+        }
+    }
+
+    /// Mark the invoice paid like lightningd does once the htlcs were released,
+    /// returns the `invoice_payment` notification.
+    pub fn pay(&self, payment_hash: &str) -> serde_json::Value {
+        let mut node = self.node.lock();
+        let inv = node
+            .invoices
+            .iter_mut()
+            .find(|inv| inv.payment_hash == payment_hash)
+            .unwrap();
+        inv.status = "paid";
+        json!({
+            "invoice_payment": {
+                "label": inv.label,
+                "preimage": inv.preimage,
+                "msat": format!("{}msat", inv.amount_msat.unwrap_or_default()),
+            }
+        })
+    }
+
+    pub fn datastore_keys(&self) -> Vec<Vec<String>> {
+        self.node.lock().datastore.keys().cloned().collect()
+    }
+
+    fn handle(&self, request: Request) -> Result<Response, RpcError> {
+        let mut node = self.node.lock();
+        match request {
+            Request::Invoice(req) => {
+                if node.invoices.iter().any(|inv| inv.label == req.label) {
+                    return Err(error(900, "Duplicate label"));
+                }
+                let preimage = match req.preimage {
+                    Some(p) => p,
+                    None => hex::encode(rand::random::<[u8; 32]>()),
+                };
+                let payment_hash =
+                    hex::encode(sha256::Hash::hash(&hex::decode(&preimage).unwrap()).into_inner());
+                let invoice = Invoice {
+                    label: req.label,
+                    description: req.description,
+                    payment_hash: payment_hash.clone(),
+                    preimage,
+                    amount_msat: match req.amount_msat {
+                        AmountOrAny::Amount(a) => Some(a.msat()),
+                        AmountOrAny::Any => None,
+                    },
+                    expires_at: now() + req.expiry.unwrap_or(604_800),
+                    status: "unpaid",
+                };
+                node.invoices.push(invoice.clone());
+                response(
+                    Response::Invoice,
+                    json!({
+                        "bolt11": bolt11(&invoice),
+                        "payment_hash": payment_hash,
+                        "payment_secret": hex::encode([0x11; 32]),
+                        "expires_at": invoice.expires_at,
+                    }),
+                )
+            }
+            Request::ListInvoices(ListinvoicesRequest {
+                label,
+                payment_hash,
+                ..
+            }) => {
+                let invoices: Vec<serde_json::Value> = node
+                    .invoices
+                    .iter()
+                    .filter(|inv| label.as_ref().map_or(true, |l| l == &inv.label))
+                    .filter(|inv| {
+                        payment_hash
+                            .as_ref()
+                            .map_or(true, |ph| ph == &inv.payment_hash)
+                    })
+                    .map(|inv| {
+                        let mut i = json!({
+                            "label": inv.label,
+                            "description": inv.description,
+                            "bolt11": bolt11(inv),
+                            "payment_hash": inv.payment_hash,
+                            "status": status(inv),
+                            "expires_at": inv.expires_at,
+                        });
+                        if let Some(msat) = inv.amount_msat {
+                            i["amount_msat"] = json!(msat);
+                        }
+                        if inv.status == "paid" {
+                            i["payment_preimage"] = json!(inv.preimage);
+                        }
+                        i
+                    })
+                    .collect();
+                response(Response::ListInvoices, json!({ "invoices": invoices }))
+            }
+            Request::Datastore(req) => {
+                let string = req.string.unwrap_or_default();
+                let existing = node.datastore.get(&req.key).cloned();
+                if let (Some(gen), Some((_, current))) = (req.generation, &existing) {
+                    if gen != *current {
+                        return Err(error(1204, "generation mismatch"));
+                    }
+                }
+                let mode = req.mode.unwrap_or(DatastoreMode::MUST_CREATE);
+                let generation = match (mode, existing) {
+                    (DatastoreMode::MUST_CREATE, Some(_)) => {
+                        return Err(error(1202, "already exists"))
+                    }
+                    (DatastoreMode::MUST_REPLACE, None) => {
+                        return Err(error(1203, "does not exist"))
+                    }
+                    (_, Some((_, gen))) => gen + 1,
+                    (_, None) => 0,
+                };
+                node.datastore
+                    .insert(req.key.clone(), (string.clone(), generation));
+                response(
+                    Response::Datastore,
+                    json!({ "key": req.key, "generation": generation, "string": string }),
+                )
+            }
+            Request::ListDatastore(req) => {
+                let prefix = req.key.unwrap_or_default();
+                let entries: Vec<serde_json::Value> = match node.datastore.get(&prefix) {
+                    Some((string, generation)) => {
+                        vec![json!({ "key": prefix, "generation": generation, "string": string })]
+                    }
+                    None => {
+                        // like lightningd only return the direct children of `prefix`
+                        let mut children: BTreeMap<Vec<String>, serde_json::Value> =
+                            BTreeMap::new();
+                        for (key, (string, generation)) in node.datastore.iter() {
+                            if key.len() > prefix.len() && key.starts_with(&prefix) {
+                                let child = key[..prefix.len() + 1].to_vec();
+                                let entry = if child == *key {
+                                    json!({ "key": key, "generation": generation, "string": string })
+                                } else {
+                                    json!({ "key": child })
+                                };
+                                children.entry(child).or_insert(entry);
+                            }
+                        }
+                        children.into_values().collect()
+                    }
+                };
+                response(Response::ListDatastore, json!({ "datastore": entries }))
+            }
+            Request::DelDatastore(req) => match node.datastore.remove(&req.key) {
+                Some((string, generation)) => response(
+                    Response::DelDatastore,
+                    json!({ "key": req.key, "generation": generation, "string": string }),
+                ),
+                None => Err(error(1200, "does not exist")),
+            },
+            Request::DelInvoice(req) => {
+                let index = node
+                    .invoices
+                    .iter()
+                    .position(|inv| inv.label == req.label)
+                    .ok_or(error(905, "Unknown invoice"))?;
+                let inv = node.invoices.remove(index);
+                response(
+                    Response::DelInvoice,
+                    json!({
+                        "label": inv.label,
+                        "payment_hash": inv.payment_hash,
+                        "status": status(&inv),
+                    }),
+                )
+            }
+            Request::SignInvoice(req) => {
+                response(Response::SignInvoice, json!({ "bolt11": req.invstring }))
+            }
+            Request::Getinfo(_) => response(
+                Response::Getinfo,
+                json!({
+                    "id": "02".to_string() + &"00".repeat(32),
+                    "alias": "fake",
+                    "color": "000000",
+                    "num_peers": 0,
+                    "num_pending_channels": 0,
+                    "num_active_channels": 0,
+                    "num_inactive_channels": 0,
+                    "version": "fake",
+                    "lightning-dir": "/tmp",
+                    "blockheight": node.blockheight,
+                    "network": "regtest",
+                    "fees_collected_msat": 0,
+                    "address": [],
+                    "binding": [],
+                }),
+            ),
+            Request::ListConfigs(_) => response(Response::ListConfigs, json!({ "cltv-delta": 40 })),
+            other => Err(error(-32601, &format!("not implemented: {:?}", other))),
+        }
+    }
+}
+
+impl Rpc for FakeLightningd {
+    fn call(&self, request: Request) -> RpcFuture<'_> {
+        Box::pin(async move {
+            // let concurrent calls interleave like they would over the socket
+            tokio::task::yield_now().await;
+            self.handle(request)
+        })
+    }
+}
+
+fn response<T: serde::de::DeserializeOwned>(
+    variant: fn(T) -> Response,
+    v: serde_json::Value,
+) -> Result<Response, RpcError> {
+    serde_json::from_value(v)
+        .map(variant)
+        .map_err(|e| error(-1, &e.to_string()))
+}
+
+fn error(code: i32, message: &str) -> RpcError {
+    RpcError {
+        code: Some(code),
+        message: message.to_string(),
+        data: None,
+    }
+}
+
+fn status(inv: &Invoice) -> &'static str {
+    if inv.status == "unpaid" && inv.expires_at <= now() {
+        "expired"
+    } else {
+        inv.status
+    }
+}
+
+fn bolt11(inv: &Invoice) -> String {
+    format!("lnbcrt1fake{}", &inv.payment_hash[..16])
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// A plugin state talking to `lightningd`, at block height `BLOCKHEIGHT`.
+pub fn plugin_state(lightningd: &Arc<FakeLightningd>) -> PluginState {
+    let state = PluginState::new(lightningd.clone());
+    handle_block(&state, json!({"block": {"height": BLOCKHEIGHT}})).unwrap();
+    state
+}
+
+/// Add a hold-invoice for 1000msat, returns its payment_hash.
+pub async fn add(state: &PluginState, label: &str) -> String {
+    add_with(
+        state,
+        json!({"amount_msat": 1000, "label": label, "description": ""}),
+    )
+    .await
+}
+
+/// Add a hold-invoice with the `hodlvoice-add` parameters `args`, returns its payment_hash.
+pub async fn add_with(state: &PluginState, args: serde_json::Value) -> String {
+    let result = add_hodlvoice(state, args).await.unwrap();
+    result["payment_hash"].as_str().unwrap().to_string()
+}
+
+/// The error lightningd gets for a failed rpc call, with the code it passes on to the caller.
+pub fn rpc_error(result: Result<serde_json::Value, anyhow::Error>) -> RpcError {
+    hodlvoice::error::rpc_error(result.unwrap_err())
+        .downcast::<RpcError>()
+        .expect("not an RpcError")
+}
+
+/// The `htlc_accepted` payload for a part of a payment.
+pub fn htlc(payment_hash: &str, id: u64, amount_msat: u64, total_msat: u64) -> serde_json::Value {
+    json!({
+        "onion": {
+            "payload": "",
+            "total_msat": format!("{}msat", total_msat),
+            "payment_secret": hex::encode([0x11; 32]),
+        },
+        "htlc": {
+            "short_channel_id": "100x1x0",
+            "id": id,
+            "amount_msat": format!("{}msat", amount_msat),
+            // enough to hold for the default hold window
+            "cltv_expiry": BLOCKHEIGHT + 40 + 18 + 144,
+            "payment_hash": payment_hash,
+        },
+    })
+}
+
+/// Wait until the stored state of `payment_hash` is `hodlstate`, panics after a few seconds.
+pub async fn wait_for_state(state: &PluginState, payment_hash: &str, hodlstate: Hodlstate) {
+    for _ in 0..500 {
+        let record = get_record(state.rpc.as_ref(), payment_hash).await.unwrap();
+        if record.map(|r| r.state) == Some(hodlstate.clone()) {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("state {} not reached", hodlstate);
+}
+
+/// Wait until `condition` holds, panics after a few seconds.
+pub async fn wait_until(condition: impl Fn() -> bool) {
+    for _ in 0..500 {
+        if condition() {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("condition not reached");
+}
@@ -0,0 +1,151 @@
+mod common;
+
+use common::{add, plugin_state, rpc_error, wait_for_state, FakeLightningd, BLOCKHEIGHT};
+use hodlvoice::{
+    accept_hodlvoice, add_hodlvoice, cancel_hodlvoice,
+    error::{
+        HodlError, EVENTS_MISSING, ILLEGAL_TRANSITION, INVALID_PARAMS, INVOICE_EXPIRED,
+        INVOICE_PAID, LIGHTNINGD_ERROR, TIMED_OUT, UNKNOWN_HODLVOICE,
+    },
+    events::{load_event_index, waitany_hodlvoice},
+    hooks::handle_block,
+    lookup::lookup_hodlvoice,
+    reject_hodlvoice, Hodlstate,
+};
+use serde_json::json;
+
+#[tokio::test]
+async fn unknown_hodlvoice() {
+    let lightningd = FakeLightningd::new();
+    let state = plugin_state(&lightningd);
+
+    let e = rpc_error(accept_hodlvoice(&state, json!({"payment_hash": "00".repeat(32)})).await);
+    assert_eq!(e.code, Some(UNKNOWN_HODLVOICE));
+    let e = rpc_error(lookup_hodlvoice(&state, json!({"label": "nothing"})).await);
+    assert_eq!(e.code, Some(UNKNOWN_HODLVOICE));
+}
+
+#[tokio::test]
+async fn illegal_transition_names_the_current_state() {
+    let lightningd = FakeLightningd::new();
+    let state = plugin_state(&lightningd);
+    let ph = add(&state, "decided").await;
+
+    reject_hodlvoice(&state, json!([ph])).await.unwrap();
+    let e = rpc_error(accept_hodlvoice(&state, json!([ph])).await);
+    assert_eq!(e.code, Some(ILLEGAL_TRANSITION));
+    assert_eq!(
+        e.data,
+        Some(json!({"payment_hash": ph, "state": "canceled", "requested": "accepted"}))
+    );
+}
+
+#[tokio::test]
+async fn expired_hodlvoice() {
+    let lightningd = FakeLightningd::new();
+    let state = plugin_state(&lightningd);
+    let ph = add(&state, "late").await;
+    lightningd.set_expiry(&ph, 0);
+    handle_block(&state, json!({"block": {"height": BLOCKHEIGHT + 1}})).unwrap();
+    wait_for_state(&state, &ph, Hodlstate::Expired).await;
+
+    let e = accept_hodlvoice(&state, json!([ph])).await.unwrap_err();
+    assert!(matches!(
+        e.downcast_ref::<HodlError>(),
+        Some(HodlError::InvoiceExpired { .. })
+    ));
+    assert_eq!(rpc_error(Err(e)).code, Some(INVOICE_EXPIRED));
+}
+
+#[tokio::test]
+async fn lightningd_error_keeps_its_code() {
+    let lightningd = FakeLightningd::new();
+    let state = plugin_state(&lightningd);
+    add(&state, "twice").await;
+
+    let e = rpc_error(
+        add_hodlvoice(
+            &state,
+            json!({"amount_msat": 1000, "label": "twice", "description": ""}),
+        )
+        .await,
+    );
+    assert_eq!(e.code, Some(LIGHTNINGD_ERROR));
+    assert_eq!(e.data, Some(json!({"method": "invoice", "code": 900})));
+}
+
+#[tokio::test]
+async fn batch_results_carry_the_code() {
+    let lightningd = FakeLightningd::new();
+    let state = plugin_state(&lightningd);
+    let ph = add(&state, "batch").await;
+
+    let result = accept_hodlvoice(&state, json!([[ph, "00".repeat(32), "zz"]]))
+        .await
+        .unwrap();
+    assert_eq!(result["results"][0]["result"], json!("success"));
+    assert_eq!(result["results"][1]["code"], json!(UNKNOWN_HODLVOICE));
+    assert_eq!(result["results"][2]["code"], json!(UNKNOWN_HODLVOICE));
+
+    let e = rpc_error(accept_hodlvoice(&state, json!({"payment_hash": 1})).await);
+    assert_eq!(e.code, Some(INVALID_PARAMS));
+}
+
+#[tokio::test]
+async fn wait_timeout() {
+    let lightningd = FakeLightningd::new();
+    let state = plugin_state(&lightningd);
+
+    let e = rpc_error(waitany_hodlvoice(&state, json!({"timeout": 0})).await);
+    assert_eq!(e.code, Some(TIMED_OUT));
+}
+
+#[tokio::test]
+async fn waitany_refuses_events_from_before_a_restart() {
+    let lightningd = FakeLightningd::new();
+    let state = plugin_state(&lightningd);
+    for label in ["first", "second"] {
+        let ph = add(&state, label).await;
+        reject_hodlvoice(&state, json!([ph])).await.unwrap();
+    }
+    let event = waitany_hodlvoice(&state, json!({"lastindex": 0}))
+        .await
+        .unwrap();
+    assert_eq!(event["index"], 1);
+
+    let restarted = plugin_state(&lightningd);
+    load_event_index(&restarted).await.unwrap();
+    let e = rpc_error(waitany_hodlvoice(&restarted, json!({"lastindex": 1})).await);
+    assert_eq!(e.code, Some(EVENTS_MISSING));
+    assert_eq!(e.data, Some(json!({"last_index": 2})));
+
+    // nothing is missing after the last index
+    let e = rpc_error(waitany_hodlvoice(&restarted, json!({"lastindex": 2, "timeout": 0})).await);
+    assert_eq!(e.code, Some(TIMED_OUT));
+}
+
+#[tokio::test]
+async fn paid_invoice_is_not_deleted() {
+    let lightningd = FakeLightningd::new();
+    let state = plugin_state(&lightningd);
+    let ph = add(&state, "paid").await;
+    reject_hodlvoice(&state, json!([ph])).await.unwrap();
+    lightningd.pay(&ph);
+
+    let e = rpc_error(cancel_hodlvoice(&state, json!([ph])).await);
+    assert_eq!(e.code, Some(INVOICE_PAID));
+    assert_eq!(e.data, Some(json!({"payment_hash": ph})));
+}
+
+#[tokio::test]
+async fn external_invoice_amount_too_large() {
+    let lightningd = FakeLightningd::new();
+    let state = plugin_state(&lightningd);
+    let e = add_hodlvoice(
+        &state,
+        json!({"amount_msat": u64::MAX, "label": "huge", "description": "", "payment_hash": "42".repeat(32)}),
+    )
+    .await
+    .unwrap_err();
+    assert!(e.to_string().contains("amount too large"), "{}", e);
+}
@@ -0,0 +1,93 @@
+mod common;
+
+use common::{add, plugin_state, wait_for_state, FakeLightningd, BLOCKHEIGHT};
+use hodlvoice::{
+    accept_hodlvoice, cancel_hodlvoice,
+    gc::gc_hodlvoices,
+    hooks::{handle_block, handle_invoice_payment},
+    record::get_record,
+    reject_hodlvoice, Hodlstate,
+};
+use serde_json::json;
+
+fn deleted(result: &serde_json::Value) -> Vec<String> {
+    let mut deleted: Vec<String> = result["deleted"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|d| d["label"].as_str().unwrap().to_string())
+        .collect();
+    deleted.sort();
+    deleted
+}
+
+#[tokio::test]
+async fn gc_deletes_finished_hodlvoices() {
+    let lightningd = FakeLightningd::new();
+    let state = plugin_state(&lightningd);
+    state.config.lock().retention_seconds.1 = 0;
+
+    add(&state, "open").await;
+    let settled = add(&state, "settled").await;
+    accept_hodlvoice(&state, json!([settled])).await.unwrap();
+    handle_invoice_payment(&state, lightningd.pay(&settled))
+        .await
+        .unwrap();
+    let canceled = add(&state, "canceled").await;
+    cancel_hodlvoice(&state, json!([canceled])).await.unwrap();
+    let expired = add(&state, "expired").await;
+    lightningd.set_expiry(&expired, 0);
+    handle_block(&state, json!({"block": {"height": BLOCKHEIGHT + 1}})).unwrap();
+    wait_for_state(&state, &expired, Hodlstate::Expired).await;
+
+    let result = gc_hodlvoices(&state, json!({"dry_run": true}))
+        .await
+        .unwrap();
+    assert_eq!(result["dry_run"], json!(true));
+    assert_eq!(deleted(&result), vec!["canceled", "expired", "settled"]);
+    assert!(get_record(state.rpc.as_ref(), &settled)
+        .await
+        .unwrap()
+        .is_some());
+
+    let result = gc_hodlvoices(&state, json!({})).await.unwrap();
+    assert_eq!(deleted(&result), vec!["canceled", "expired", "settled"]);
+    for ph in [settled, canceled, expired] {
+        assert!(get_record(state.rpc.as_ref(), &ph).await.unwrap().is_none());
+    }
+    let result = gc_hodlvoices(&state, json!({})).await.unwrap();
+    assert!(deleted(&result).is_empty());
+}
+
+#[tokio::test]
+async fn gc_keeps_rejected_hodlvoice_with_unpaid_invoice() {
+    let lightningd = FakeLightningd::new();
+    let state = plugin_state(&lightningd);
+    state.config.lock().retention_seconds.1 = 0;
+
+    let rejected = add(&state, "rejected").await;
+    reject_hodlvoice(&state, json!([rejected])).await.unwrap();
+    let result = gc_hodlvoices(&state, json!({})).await.unwrap();
+    assert!(deleted(&result).is_empty());
+    assert!(get_record(state.rpc.as_ref(), &rejected)
+        .await
+        .unwrap()
+        .is_some());
+
+    // can't be paid anymore once it expired
+    lightningd.set_expiry(&rejected, 0);
+    let result = gc_hodlvoices(&state, json!({})).await.unwrap();
+    assert_eq!(deleted(&result), vec!["rejected"]);
+}
+
+#[tokio::test]
+async fn gc_keeps_hodlvoices_within_retention() {
+    let lightningd = FakeLightningd::new();
+    let state = plugin_state(&lightningd);
+
+    let canceled = add(&state, "recent").await;
+    cancel_hodlvoice(&state, json!([canceled])).await.unwrap();
+    let result = gc_hodlvoices(&state, json!([])).await.unwrap();
+    assert_eq!(result["dry_run"], json!(false));
+    assert!(deleted(&result).is_empty());
+}
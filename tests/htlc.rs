@@ -0,0 +1,610 @@
+mod common;
+
+use bitcoin::hashes::{sha256, Hash};
+use common::{
+    add_with, htlc, now, plugin_state, wait_for_state, wait_until, FakeLightningd, BLOCKHEIGHT,
+};
+use hodlvoice::{
+    accept_hodlvoice, add_hodlvoice, cancel_hodlvoice,
+    config::PluginState,
+    hooks::{handle_block, handle_htlc, handle_invoice_payment},
+    lookup::lookup_hodlvoice,
+    record::get_record,
+    reject_hodlvoice, settle_hodlvoice, Hodlstate,
+};
+use serde_json::json;
+use tokio::task::JoinHandle;
+
+fn hold(state: &PluginState, v: serde_json::Value) -> JoinHandle<serde_json::Value> {
+    let state = state.clone();
+    tokio::spawn(async move { handle_htlc(&state, v).await.unwrap() })
+}
+
+async fn stored_state(state: &PluginState, payment_hash: &str) -> Hodlstate {
+    get_record(state.rpc.as_ref(), payment_hash)
+        .await
+        .unwrap()
+        .unwrap()
+        .state
+}
+
+#[tokio::test]
+async fn accept_releases_held_htlc() {
+    let lightningd = FakeLightningd::new();
+    let state = plugin_state(&lightningd);
+    let ph = add_with(
+        &state,
+        json!({"amount_msat": 1000, "label": "accept", "description": ""}),
+    )
+    .await;
+
+    assert_eq!(stored_state(&state, &ph).await, Hodlstate::Open);
+
+    let handle = hold(&state, htlc(&ph, 0, 1000, 1000));
+    wait_for_state(&state, &ph, Hodlstate::Held).await;
+    assert!(!handle.is_finished());
+
+    accept_hodlvoice(&state, json!([ph])).await.unwrap();
+    assert_eq!(handle.await.unwrap(), json!({"result": "continue"}));
+    assert_eq!(stored_state(&state, &ph).await, Hodlstate::Accepted);
+
+    handle_invoice_payment(&state, lightningd.pay(&ph))
+        .await
+        .unwrap();
+    assert_eq!(stored_state(&state, &ph).await, Hodlstate::Settled);
+}
+
+#[tokio::test]
+async fn reject_fails_held_htlc() {
+    let lightningd = FakeLightningd::new();
+    let state = plugin_state(&lightningd);
+    let ph = add_with(
+        &state,
+        json!({"amount_msat": 1000, "label": "reject", "description": ""}),
+    )
+    .await;
+
+    let handle = hold(&state, htlc(&ph, 0, 1000, 1000));
+    wait_until(|| state.is_fully_held(&ph)).await;
+
+    reject_hodlvoice(&state, json!([ph])).await.unwrap();
+    assert_eq!(handle.await.unwrap(), json!({"result": "fail"}));
+    assert_eq!(stored_state(&state, &ph).await, Hodlstate::Canceled);
+}
+
+#[tokio::test]
+async fn unknown_payment_hash_continues() {
+    let lightningd = FakeLightningd::new();
+    let state = plugin_state(&lightningd);
+
+    let result = handle_htlc(&state, htlc(&"ab".repeat(32), 0, 1000, 1000))
+        .await
+        .unwrap();
+    assert_eq!(result, json!({"result": "continue"}));
+}
+
+#[tokio::test]
+async fn invalid_cltv_expiry_is_an_error() {
+    let lightningd = FakeLightningd::new();
+    let state = plugin_state(&lightningd);
+    let ph = add_with(
+        &state,
+        json!({"amount_msat": 1000, "label": "invalid", "description": ""}),
+    )
+    .await;
+
+    let mut v = htlc(&ph, 0, 1000, 1000);
+    v["htlc"]["cltv_expiry"] = json!("soon");
+    assert!(handle_htlc(&state, v).await.is_err());
+    assert!(!state.is_fully_held(&ph));
+}
+
+#[tokio::test]
+async fn multi_part_payment_waits_for_all_parts() {
+    let lightningd = FakeLightningd::new();
+    let state = plugin_state(&lightningd);
+    let ph = add_with(
+        &state,
+        json!({"amount_msat": 1000, "label": "mpp", "description": ""}),
+    )
+    .await;
+    accept_hodlvoice(&state, json!([ph])).await.unwrap();
+
+    let first = hold(&state, htlc(&ph, 0, 600, 1000));
+    wait_until(|| state.held_msat(&ph) == 600).await;
+    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    assert!(!first.is_finished());
+
+    let second = hold(&state, htlc(&ph, 1, 400, 1000));
+    assert_eq!(first.await.unwrap(), json!({"result": "continue"}));
+    assert_eq!(second.await.unwrap(), json!({"result": "continue"}));
+}
+
+#[tokio::test]
+async fn rejected_part_does_not_complete_a_partial_payment() {
+    let lightningd = FakeLightningd::new();
+    let state = plugin_state(&lightningd);
+    let ph = add_with(
+        &state,
+        json!({"amount_msat": 1000, "label": "bogus", "description": ""}),
+    )
+    .await;
+
+    let first = hold(&state, htlc(&ph, 0, 600, 1000));
+    wait_until(|| state.held_msat(&ph) == 600).await;
+    // announces less than the invoice amount, so it is failed right away
+    let bogus = handle_htlc(&state, htlc(&ph, 1, 600, 500)).await.unwrap();
+    assert_eq!(bogus, json!({"result": "fail"}));
+    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    assert!(!state.is_fully_held(&ph));
+    assert_eq!(stored_state(&state, &ph).await, Hodlstate::Open);
+
+    accept_hodlvoice(&state, json!([ph])).await.unwrap();
+    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    assert!(!first.is_finished());
+
+    let second = hold(&state, htlc(&ph, 2, 400, 1000));
+    assert_eq!(first.await.unwrap(), json!({"result": "continue"}));
+    assert_eq!(second.await.unwrap(), json!({"result": "continue"}));
+}
+
+#[tokio::test]
+async fn invoice_expiry_fails_htlc() {
+    let lightningd = FakeLightningd::new();
+    let state = plugin_state(&lightningd);
+    let ph = add_with(
+        &state,
+        json!({"amount_msat": 1000, "label": "expiry", "description": ""}),
+    )
+    .await;
+    lightningd.set_expiry(&ph, 1);
+
+    let handle = hold(&state, htlc(&ph, 0, 1000, 1000));
+    assert_eq!(handle.await.unwrap(), json!({"result": "fail"}));
+    assert_eq!(stored_state(&state, &ph).await, Hodlstate::Expired);
+}
+
+#[tokio::test]
+async fn decided_payment_is_released_after_expiry() {
+    let lightningd = FakeLightningd::new();
+    let state = plugin_state(&lightningd);
+    let ph = add_with(
+        &state,
+        json!({"amount_msat": 1000, "label": "late-part", "description": ""}),
+    )
+    .await;
+    accept_hodlvoice(&state, json!([ph])).await.unwrap();
+
+    let first = hold(&state, htlc(&ph, 0, 600, 1000));
+    wait_until(|| state.held_msat(&ph) == 600).await;
+    // the last part sees the invoice expired, the payment is complete and decided though
+    lightningd.set_expiry(&ph, 0);
+    let second = hold(&state, htlc(&ph, 1, 400, 1000));
+    assert_eq!(first.await.unwrap(), json!({"result": "continue"}));
+    assert_eq!(second.await.unwrap(), json!({"result": "continue"}));
+    assert_eq!(stored_state(&state, &ph).await, Hodlstate::Accepted);
+}
+
+#[tokio::test]
+async fn cltv_deadline_fails_htlc() {
+    let lightningd = FakeLightningd::new();
+    let state = plugin_state(&lightningd);
+    let ph = add_with(
+        &state,
+        json!({"amount_msat": 1000, "label": "cltv", "description": ""}),
+    )
+    .await;
+
+    let mut v = htlc(&ph, 0, 1000, 1000);
+    // cltv-delta 40 and 18 safety blocks, so the deadline is the next block
+    v["htlc"]["cltv_expiry"] = json!(BLOCKHEIGHT + 40 + 18 + 1);
+    let handle = hold(&state, v);
+    wait_until(|| state.is_fully_held(&ph)).await;
+    assert!(!handle.is_finished());
+
+    handle_block(&state, json!({"block": {"height": BLOCKHEIGHT + 1}})).unwrap();
+    assert_eq!(handle.await.unwrap(), json!({"result": "fail"}));
+    assert_eq!(stored_state(&state, &ph).await, Hodlstate::Expired);
+}
+
+#[tokio::test]
+async fn extra_cltv_does_not_extend_the_hold() {
+    let lightningd = FakeLightningd::new();
+    let state = plugin_state(&lightningd);
+    let ph = add_with(
+        &state,
+        json!({"amount_msat": 1000, "label": "extra-cltv", "description": "", "hold_blocks": 6}),
+    )
+    .await;
+
+    let mut v = htlc(&ph, 0, 1000, 1000);
+    v["htlc"]["cltv_expiry"] = json!(BLOCKHEIGHT + 40 + 18 + 1000);
+    let handle = hold(&state, v);
+    wait_until(|| state.is_fully_held(&ph)).await;
+    wait_until(|| state.held_htlcs(&ph)[0].deadline_blockheight == BLOCKHEIGHT + 6).await;
+
+    handle_block(&state, json!({"block": {"height": BLOCKHEIGHT + 5}})).unwrap();
+    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    assert!(!handle.is_finished());
+
+    handle_block(&state, json!({"block": {"height": BLOCKHEIGHT + 6}})).unwrap();
+    assert_eq!(handle.await.unwrap(), json!({"result": "fail"}));
+    assert_eq!(stored_state(&state, &ph).await, Hodlstate::Expired);
+}
+
+#[tokio::test]
+async fn reorg_does_not_fail_htlc() {
+    let lightningd = FakeLightningd::new();
+    let state = plugin_state(&lightningd);
+    let ph = add_with(
+        &state,
+        json!({"amount_msat": 1000, "label": "reorg", "description": ""}),
+    )
+    .await;
+
+    let mut v = htlc(&ph, 0, 1000, 1000);
+    v["htlc"]["cltv_expiry"] = json!(BLOCKHEIGHT + 40 + 18 + 2);
+    let handle = hold(&state, v);
+    wait_until(|| state.is_fully_held(&ph)).await;
+
+    handle_block(&state, json!({"block": {"height": BLOCKHEIGHT + 1}})).unwrap();
+    handle_block(&state, json!({"block": {"height": BLOCKHEIGHT}})).unwrap();
+    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    assert!(!handle.is_finished());
+
+    accept_hodlvoice(&state, json!([ph])).await.unwrap();
+    assert_eq!(handle.await.unwrap(), json!({"result": "continue"}));
+}
+
+#[tokio::test]
+async fn on_timeout_accept_releases_htlc() {
+    let lightningd = FakeLightningd::new();
+    let state = plugin_state(&lightningd);
+    let ph = add_with(
+        &state,
+        json!({"amount_msat": 1000, "label": "tip", "description": "", "on_timeout": "accept"}),
+    )
+    .await;
+
+    let mut v = htlc(&ph, 0, 1000, 1000);
+    v["htlc"]["cltv_expiry"] = json!(BLOCKHEIGHT + 40 + 18 + 1);
+    let handle = hold(&state, v);
+    wait_until(|| state.is_fully_held(&ph)).await;
+
+    handle_block(&state, json!({"block": {"height": BLOCKHEIGHT + 1}})).unwrap();
+    assert_eq!(handle.await.unwrap(), json!({"result": "continue"}));
+    assert_eq!(stored_state(&state, &ph).await, Hodlstate::Accepted);
+}
+
+#[tokio::test]
+async fn max_hold_seconds_fails_htlc() {
+    let lightningd = FakeLightningd::new();
+    let state = plugin_state(&lightningd);
+    let ph = add_with(
+        &state,
+        json!({"amount_msat": 1000, "label": "max-hold", "description": "", "max_hold_seconds": 1}),
+    )
+    .await;
+
+    let start = now();
+    let handle = hold(&state, htlc(&ph, 0, 1000, 1000));
+    assert_eq!(handle.await.unwrap(), json!({"result": "fail"}));
+    assert!(now() >= start + 1);
+    assert_eq!(stored_state(&state, &ph).await, Hodlstate::Expired);
+}
+
+#[tokio::test]
+async fn lookup_shows_when_max_hold_seconds_ends() {
+    let lightningd = FakeLightningd::new();
+    let state = plugin_state(&lightningd);
+    let ph = add_with(
+        &state,
+        json!({"amount_msat": 1000, "label": "hold-until", "description": "", "max_hold_seconds": 600}),
+    )
+    .await;
+
+    let start = now();
+    let _handle = hold(&state, htlc(&ph, 0, 1000, 1000));
+    wait_until(|| state.is_fully_held(&ph)).await;
+    let result = lookup_hodlvoice(&state, json!([ph])).await.unwrap();
+    let hold_until = result["hold_until"].as_u64().unwrap();
+    assert!(hold_until >= start + 600 && hold_until <= now() + 600);
+}
+
+#[tokio::test]
+async fn settle_resolves_external_invoice() {
+    let lightningd = FakeLightningd::new();
+    let state = plugin_state(&lightningd);
+    let preimage = [0x42u8; 32];
+    let ph = hex::encode(sha256::Hash::hash(&preimage).into_inner());
+    let result = add_hodlvoice(
+        &state,
+        json!({"amount_msat": 1000, "label": "external", "description": "", "payment_hash": ph}),
+    )
+    .await
+    .unwrap();
+
+    let mut v = htlc(&ph, 0, 1000, 1000);
+    v["onion"]["payment_secret"] = result["payment_secret"].clone();
+    let handle = hold(&state, v);
+    wait_until(|| state.is_fully_held(&ph)).await;
+
+    assert!(accept_hodlvoice(&state, json!([ph])).await.is_err());
+    settle_hodlvoice(&state, json!([ph, hex::encode(preimage)]))
+        .await
+        .unwrap();
+    assert_eq!(
+        handle.await.unwrap(),
+        json!({"result": "resolve", "payment_key": hex::encode(preimage)})
+    );
+    assert_eq!(stored_state(&state, &ph).await, Hodlstate::Settled);
+}
+
+#[tokio::test]
+async fn settle_leaves_invoice_of_lightningd_to_lightningd() {
+    let lightningd = FakeLightningd::new();
+    let state = plugin_state(&lightningd);
+    let preimage = "42".repeat(32);
+    let result = add_hodlvoice(
+        &state,
+        json!({"amount_msat": 1000, "label": "own", "description": "", "preimage": preimage}),
+    )
+    .await
+    .unwrap();
+    let ph = result["payment_hash"].as_str().unwrap().to_string();
+
+    let handle = hold(&state, htlc(&ph, 0, 1000, 1000));
+    wait_until(|| state.is_fully_held(&ph)).await;
+    settle_hodlvoice(&state, json!([ph, preimage]))
+        .await
+        .unwrap();
+    assert_eq!(handle.await.unwrap(), json!({"result": "continue"}));
+    let record = get_record(state.rpc.as_ref(), &ph).await.unwrap().unwrap();
+    assert_eq!(record.state, Hodlstate::Accepted);
+    assert_eq!(record.preimage, None);
+}
+
+#[tokio::test]
+async fn new_block_expires_unpaid_invoice() {
+    let lightningd = FakeLightningd::new();
+    let state = plugin_state(&lightningd);
+    let ph = add_with(
+        &state,
+        json!({"amount_msat": 1000, "label": "unpaid", "description": ""}),
+    )
+    .await;
+    lightningd.set_expiry(&ph, 0);
+
+    handle_block(&state, json!({"block": {"height": BLOCKHEIGHT + 1}})).unwrap();
+    wait_for_state(&state, &ph, Hodlstate::Expired).await;
+}
+
+#[tokio::test]
+async fn payment_of_rejected_invoice_is_settled() {
+    let lightningd = FakeLightningd::new();
+    let state = plugin_state(&lightningd);
+    let ph = add_with(
+        &state,
+        json!({"amount_msat": 1000, "label": "inconsistent", "description": ""}),
+    )
+    .await;
+    let mut notifications = state.notifications.subscribe();
+    reject_hodlvoice(&state, json!([ph])).await.unwrap();
+
+    handle_invoice_payment(&state, lightningd.pay(&ph))
+        .await
+        .unwrap();
+    assert_eq!(stored_state(&state, &ph).await, Hodlstate::Settled);
+    let mut topics = Vec::new();
+    while let Ok(n) = notifications.try_recv() {
+        topics.push(n.topic);
+    }
+    assert!(topics.contains(&"hodlvoice_inconsistency".to_string()));
+}
+
+#[tokio::test]
+async fn hold_blocks_longer_than_expiry_is_refused() {
+    let lightningd = FakeLightningd::new();
+    let state = plugin_state(&lightningd);
+
+    let result = add_hodlvoice(
+        &state,
+        json!({"amount_msat": 1000, "label": "long", "description": "", "expiry": 3600, "hold_blocks": 144}),
+    )
+    .await;
+    assert!(result.is_err());
+    assert!(lightningd.datastore_keys().is_empty());
+}
+
+#[tokio::test]
+async fn underpayment_fails_without_holding() {
+    let lightningd = FakeLightningd::new();
+    let state = plugin_state(&lightningd);
+    let ph = add_with(
+        &state,
+        json!({"amount_msat": 1000, "label": "underpaid", "description": ""}),
+    )
+    .await;
+
+    let result = handle_htlc(&state, htlc(&ph, 0, 600, 600)).await.unwrap();
+    assert_eq!(result, json!({"result": "fail"}));
+    assert_eq!(state.held_msat(&ph), 0);
+    assert_eq!(stored_state(&state, &ph).await, Hodlstate::Open);
+}
+
+#[tokio::test]
+async fn overpayment_above_tolerance_fails() {
+    let lightningd = FakeLightningd::new();
+    let state = plugin_state(&lightningd);
+    state.config.lock().overpayment_percent.1 = 10;
+    let ph = add_with(
+        &state,
+        json!({"amount_msat": 1000, "label": "overpaid", "description": ""}),
+    )
+    .await;
+
+    let result = handle_htlc(&state, htlc(&ph, 0, 1200, 1200)).await.unwrap();
+    assert_eq!(result, json!({"result": "fail"}));
+
+    let handle = hold(&state, htlc(&ph, 1, 1100, 1100));
+    wait_for_state(&state, &ph, Hodlstate::Held).await;
+    accept_hodlvoice(&state, json!([ph])).await.unwrap();
+    assert_eq!(handle.await.unwrap(), json!({"result": "continue"}));
+}
+
+#[tokio::test]
+async fn cancel_fails_htlc_and_deletes_invoice() {
+    let lightningd = FakeLightningd::new();
+    let state = plugin_state(&lightningd);
+    let ph = add_with(
+        &state,
+        json!({"amount_msat": 1000, "label": "cancel", "description": ""}),
+    )
+    .await;
+
+    let handle = hold(&state, htlc(&ph, 0, 1000, 1000));
+    wait_for_state(&state, &ph, Hodlstate::Held).await;
+    cancel_hodlvoice(&state, json!(["cancel"])).await.unwrap();
+    assert_eq!(handle.await.unwrap(), json!({"result": "fail"}));
+    assert_eq!(stored_state(&state, &ph).await, Hodlstate::Canceled);
+
+    // a retry by the payer is failed right away
+    let result = handle_htlc(&state, htlc(&ph, 1, 1000, 1000)).await.unwrap();
+    assert_eq!(result, json!({"result": "fail"}));
+
+    // and the label can be used again
+    add_with(
+        &state,
+        json!({"amount_msat": 2000, "label": "cancel", "description": ""}),
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn cancel_after_reject_deletes_invoice() {
+    let lightningd = FakeLightningd::new();
+    let state = plugin_state(&lightningd);
+    let ph = add_with(
+        &state,
+        json!({"amount_msat": 1000, "label": "rejected", "description": ""}),
+    )
+    .await;
+    reject_hodlvoice(&state, json!([ph])).await.unwrap();
+
+    cancel_hodlvoice(&state, json!({"payment_hash": ph}))
+        .await
+        .unwrap();
+    assert!(add_hodlvoice(
+        &state,
+        json!({"amount_msat": 1000, "label": "rejected", "description": ""})
+    )
+    .await
+    .is_ok());
+
+    accept_hodlvoice(&state, json!([ph])).await.unwrap_err();
+}
+
+#[tokio::test]
+async fn cancel_after_hold_timeout_deletes_invoice() {
+    let lightningd = FakeLightningd::new();
+    let state = plugin_state(&lightningd);
+    let ph = add_with(
+        &state,
+        json!({"amount_msat": 1000, "label": "timed-out", "description": ""}),
+    )
+    .await;
+    let mut v = htlc(&ph, 0, 1000, 1000);
+    v["htlc"]["cltv_expiry"] = json!(BLOCKHEIGHT + 40 + 18 + 1);
+    let handle = hold(&state, v);
+    wait_until(|| state.is_fully_held(&ph)).await;
+    handle_block(&state, json!({"block": {"height": BLOCKHEIGHT + 1}})).unwrap();
+    assert_eq!(handle.await.unwrap(), json!({"result": "fail"}));
+    assert_eq!(stored_state(&state, &ph).await, Hodlstate::Expired);
+
+    cancel_hodlvoice(&state, json!([ph])).await.unwrap();
+    assert_eq!(stored_state(&state, &ph).await, Hodlstate::Expired);
+    assert!(add_hodlvoice(
+        &state,
+        json!({"amount_msat": 1000, "label": "timed-out", "description": ""})
+    )
+    .await
+    .is_ok());
+}
+
+#[tokio::test]
+async fn any_amount_invoice_holds_total_within_bounds() {
+    let lightningd = FakeLightningd::new();
+    let state = plugin_state(&lightningd);
+    let ph = add_with(
+        &state,
+        json!({"amount_msat": "any", "label": "donation", "description": "", "min_msat": "1sat", "max_msat": "0.00000005btc"}),
+    )
+    .await;
+
+    let result = handle_htlc(&state, htlc(&ph, 0, 500, 500)).await.unwrap();
+    assert_eq!(result, json!({"result": "fail"}));
+    let result = handle_htlc(&state, htlc(&ph, 1, 6000, 6000)).await.unwrap();
+    assert_eq!(result, json!({"result": "fail"}));
+
+    let first = hold(&state, htlc(&ph, 2, 2000, 3000));
+    wait_until(|| state.held_msat(&ph) == 2000).await;
+    let second = hold(&state, htlc(&ph, 3, 1000, 3000));
+    wait_for_state(&state, &ph, Hodlstate::Held).await;
+    accept_hodlvoice(&state, json!([ph])).await.unwrap();
+    assert_eq!(first.await.unwrap(), json!({"result": "continue"}));
+    assert_eq!(second.await.unwrap(), json!({"result": "continue"}));
+}
+
+#[tokio::test]
+async fn amounts_with_units() {
+    let lightningd = FakeLightningd::new();
+    let state = plugin_state(&lightningd);
+
+    for (i, (amount, msat)) in [
+        (json!(1000), 1000),
+        (json!("1000msat"), 1000),
+        (json!("1000"), 1000),
+        (json!("2sat"), 2000),
+        (json!("2.5sat"), 2500),
+        (json!("0.0001btc"), 10_000_000),
+    ]
+    .into_iter()
+    .enumerate()
+    {
+        let ph = add_with(
+            &state,
+            json!({"amount_msat": amount, "label": format!("unit-{}", i), "description": ""}),
+        )
+        .await;
+        let result = handle_htlc(&state, htlc(&ph, 0, msat - 1, msat - 1))
+            .await
+            .unwrap();
+        assert_eq!(result, json!({"result": "fail"}), "{}", amount);
+        let handle = hold(&state, htlc(&ph, 1, msat, msat));
+        wait_for_state(&state, &ph, Hodlstate::Held).await;
+        reject_hodlvoice(&state, json!([ph])).await.unwrap();
+        handle.await.unwrap();
+    }
+
+    for amount in [
+        json!("1.2345sat"),
+        json!("1.5msat"),
+        json!("sat"),
+        json!(0),
+        json!("12abc"),
+    ] {
+        let result = add_hodlvoice(
+            &state,
+            json!({"amount_msat": amount, "label": "bad", "description": ""}),
+        )
+        .await;
+        assert!(result.is_err(), "{}", amount);
+    }
+    // bounds are only for `any` amount invoices
+    let result = add_hodlvoice(
+        &state,
+        json!({"amount_msat": 1000, "label": "bad", "description": "", "min_msat": 500}),
+    )
+    .await;
+    assert!(result.is_err());
+}
@@ -0,0 +1,85 @@
+mod common;
+
+use cln_rpc::model::DatastoreMode;
+use common::{add, htlc, plugin_state, wait_until, FakeLightningd, BLOCKHEIGHT};
+use hodlvoice::{
+    accept_hodlvoice, datastore,
+    hooks::{handle_block, handle_htlc},
+    record::{get_record, migrate_records, RECORD_VERSION},
+    Hodlstate, PLUGIN_NAME,
+};
+use serde_json::json;
+
+// what older versions stored for a hold-invoice, just its state
+async fn legacy_entry(lightningd: &FakeLightningd, payment_hash: &str, state: &str) {
+    datastore(
+        lightningd,
+        vec![PLUGIN_NAME.to_string(), payment_hash.to_string()],
+        Some(state.to_string()),
+        None,
+        Some(DatastoreMode::CREATE_OR_REPLACE),
+        None,
+    )
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn plain_states_are_migrated_to_records() {
+    let lightningd = FakeLightningd::new();
+    let state = plugin_state(&lightningd);
+    let mut hashes = Vec::new();
+    for label in ["hodl", "accept", "reject"] {
+        hashes.push(add(&state, label).await);
+    }
+    for (ph, legacy) in hashes.iter().zip(["hodl", "accept", "reject"]) {
+        legacy_entry(&lightningd, ph, legacy).await;
+    }
+
+    migrate_records(lightningd.as_ref()).await.unwrap();
+    for (ph, (label, hodlstate)) in hashes.iter().zip([
+        ("hodl", Hodlstate::Open),
+        ("accept", Hodlstate::Accepted),
+        ("reject", Hodlstate::Canceled),
+    ]) {
+        let record = get_record(lightningd.as_ref(), ph).await.unwrap().unwrap();
+        assert_eq!(record.version, RECORD_VERSION);
+        assert_eq!(record.label.as_deref(), Some(label));
+        assert_eq!(record.state, hodlstate);
+    }
+
+    // running it again leaves the records alone
+    migrate_records(lightningd.as_ref()).await.unwrap();
+    let record = get_record(lightningd.as_ref(), &hashes[1])
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(record.history.len(), 2);
+}
+
+#[tokio::test]
+async fn migrated_hodlvoice_holds_until_its_cltv_deadline() {
+    let lightningd = FakeLightningd::new();
+    let state = plugin_state(&lightningd);
+    let ph = add(&state, "legacy").await;
+    legacy_entry(&lightningd, &ph, "hodl").await;
+    migrate_records(lightningd.as_ref()).await.unwrap();
+
+    // invoices of older versions asked for cltv-delta + 200 blocks
+    let mut v = htlc(&ph, 0, 1000, 1000);
+    v["htlc"]["cltv_expiry"] = json!(BLOCKHEIGHT + 40 + 200 + 10);
+    let task_state = state.clone();
+    let handle = tokio::spawn(async move { handle_htlc(&task_state, v).await.unwrap() });
+    wait_until(|| state.is_fully_held(&ph)).await;
+    assert_eq!(
+        state.held_htlcs(&ph)[0].deadline_blockheight,
+        BLOCKHEIGHT + 10
+    );
+
+    handle_block(&state, json!({"block": {"height": BLOCKHEIGHT + 1}})).unwrap();
+    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    assert!(!handle.is_finished());
+
+    accept_hodlvoice(&state, json!([ph])).await.unwrap();
+    assert_eq!(handle.await.unwrap(), json!({"result": "continue"}));
+}
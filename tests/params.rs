@@ -0,0 +1,121 @@
+mod common;
+
+use common::{plugin_state, rpc_error, FakeLightningd};
+use hodlvoice::{
+    accept_hodlvoice, add_hodlvoice, error::INVALID_PARAMS, record::get_record, settle_hodlvoice,
+    Hodlstate,
+};
+use serde_json::json;
+
+#[tokio::test]
+async fn add_takes_positional_parameters_like_invoice() {
+    let lightningd = FakeLightningd::new();
+    let state = plugin_state(&lightningd);
+
+    let result = add_hodlvoice(&state, json!(["2sat", "positional", "coffee", 172800]))
+        .await
+        .unwrap();
+    let ph = result["payment_hash"].as_str().unwrap();
+    let record = get_record(state.rpc.as_ref(), ph).await.unwrap().unwrap();
+    assert_eq!(record.label.as_deref(), Some("positional"));
+    assert_eq!(record.state, Hodlstate::Open);
+
+    // skipped positions are sent as null by lightning-cli
+    let result = add_hodlvoice(
+        &state,
+        json!([1000, "skipped", "", null, null, null, null, null, null, null, 6]),
+    )
+    .await
+    .unwrap();
+    assert_eq!(result["hold_blocks"], json!(6));
+}
+
+#[tokio::test]
+async fn add_names_the_invalid_parameter() {
+    let lightningd = FakeLightningd::new();
+    let state = plugin_state(&lightningd);
+
+    for (args, message) in [
+        (
+            json!({"amount_msat": 1000, "label": "a", "description": "", "expiry": "soon"}),
+            "invalid `expiry`",
+        ),
+        (
+            json!({"amount_msat": 1000, "description": ""}),
+            "missing field `label`",
+        ),
+        (
+            json!({"amount_msat": 1000, "label": "a", "description": "", "color": "red"}),
+            "unknown field `color`",
+        ),
+        (
+            json!({"amount_msat": 1000, "label": "a", "description": "", "on_timeout": "later"}),
+            "invalid `on_timeout`",
+        ),
+        (
+            json!({"amount_msat": "lots", "label": "a", "description": ""}),
+            "invalid amount for amount_msat",
+        ),
+        (
+            json!({"amount_msat": 1000, "label": "a", "description": "", "cltv": 40}),
+            "`cltv` is set from the plugin options",
+        ),
+        (
+            json!([
+                1000, "a", "", null, null, null, null, null, null, null, null, null, null, null,
+                null, 1
+            ]),
+            "too many parameters",
+        ),
+    ] {
+        let e = rpc_error(add_hodlvoice(&state, args.clone()).await);
+        assert_eq!(e.code, Some(INVALID_PARAMS), "{}", args);
+        assert!(e.message.contains(message), "{}: {}", args, e.message);
+    }
+}
+
+#[tokio::test]
+async fn decisions_reject_invalid_references() {
+    let lightningd = FakeLightningd::new();
+    let state = plugin_state(&lightningd);
+
+    for args in [
+        json!([]),
+        json!({"payment_hash": [1]}),
+        json!({"payment_hash": []}),
+        json!({"payment_hash": "00", "label": "both"}),
+        json!({"hash": "00"}),
+    ] {
+        let e = rpc_error(accept_hodlvoice(&state, args.clone()).await);
+        assert_eq!(e.code, Some(INVALID_PARAMS), "{}", args);
+    }
+}
+
+#[tokio::test]
+async fn settle_takes_keyword_parameters() {
+    let lightningd = FakeLightningd::new();
+    let state = plugin_state(&lightningd);
+    let preimage = "11".repeat(32);
+    let result = add_hodlvoice(
+        &state,
+        json!({"amount_msat": 1000, "label": "settle", "description": "", "preimage": preimage}),
+    )
+    .await
+    .unwrap();
+    let ph = result["payment_hash"].as_str().unwrap();
+
+    let e = rpc_error(
+        settle_hodlvoice(
+            &state,
+            json!({"payment_hash": ph, "preimage": "22".repeat(32)}),
+        )
+        .await,
+    );
+    assert_eq!(e.code, Some(INVALID_PARAMS));
+
+    settle_hodlvoice(&state, json!({"payment_hash": ph, "preimage": preimage}))
+        .await
+        .unwrap();
+    let record = get_record(state.rpc.as_ref(), ph).await.unwrap().unwrap();
+    assert_eq!(record.state, Hodlstate::Accepted);
+}
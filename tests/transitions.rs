@@ -0,0 +1,200 @@
+mod common;
+
+use common::{add, htlc, plugin_state, rpc_error, wait_until, FakeLightningd};
+use hodlvoice::{
+    accept_hodlvoice, add_hodlvoice, events::load_event_index, hooks::handle_htlc,
+    record::get_record, reject_hodlvoice, Hodlstate,
+};
+use serde_json::json;
+
+#[tokio::test]
+async fn accept_after_reject_is_refused() {
+    let lightningd = FakeLightningd::new();
+    let state = plugin_state(&lightningd);
+    let ph = add(&state, "refused").await;
+
+    reject_hodlvoice(&state, json!([ph])).await.unwrap();
+    let err = accept_hodlvoice(&state, json!([ph])).await.unwrap_err();
+    assert!(err.to_string().contains("is already canceled"), "{}", err);
+
+    let record = get_record(state.rpc.as_ref(), &ph).await.unwrap().unwrap();
+    assert_eq!(record.state, Hodlstate::Canceled);
+    assert_eq!(record.history.len(), 2);
+}
+
+#[tokio::test]
+async fn racing_accept_and_reject_only_one_wins() {
+    let lightningd = FakeLightningd::new();
+    let state = plugin_state(&lightningd);
+    let ph = add(&state, "race").await;
+
+    let (accepted, rejected) = tokio::join!(
+        accept_hodlvoice(&state, json!([ph])),
+        reject_hodlvoice(&state, json!([ph])),
+    );
+    assert!(accepted.is_ok() != rejected.is_ok());
+
+    let record = get_record(state.rpc.as_ref(), &ph).await.unwrap().unwrap();
+    let winner = if accepted.is_ok() {
+        Hodlstate::Accepted
+    } else {
+        Hodlstate::Canceled
+    };
+    assert_eq!(record.state, winner);
+    assert_eq!(record.history.len(), 2);
+}
+
+#[tokio::test]
+async fn event_indexes_are_not_reused_after_a_restart() {
+    let lightningd = FakeLightningd::new();
+    let state = plugin_state(&lightningd);
+    let mut rejects = Vec::new();
+    for i in 0..5 {
+        let ph = add(&state, &format!("index-{}", i)).await;
+        let state = state.clone();
+        rejects.push(tokio::spawn(async move {
+            reject_hodlvoice(&state, json!([ph])).await.unwrap()
+        }));
+    }
+    for reject in rejects {
+        reject.await.unwrap();
+    }
+    let indexes: Vec<u64> = state.events.lock().events.iter().map(|e| e.index).collect();
+    assert_eq!(indexes, vec![1, 2, 3, 4, 5]);
+
+    let restarted = plugin_state(&lightningd);
+    load_event_index(&restarted).await.unwrap();
+    let ph = add(&restarted, "restarted").await;
+    reject_hodlvoice(&restarted, json!([ph])).await.unwrap();
+    assert_eq!(restarted.events.lock().events.back().unwrap().index, 6);
+}
+
+#[tokio::test]
+async fn held_htlc_follows_the_winning_decision() {
+    let lightningd = FakeLightningd::new();
+    let state = plugin_state(&lightningd);
+    let ph = add(&state, "held-race").await;
+
+    let task_state = state.clone();
+    let v = htlc(&ph, 0, 1000, 1000);
+    let handle = tokio::spawn(async move { handle_htlc(&task_state, v).await.unwrap() });
+    wait_until(|| state.is_fully_held(&ph)).await;
+
+    accept_hodlvoice(&state, json!([ph])).await.unwrap();
+    assert!(reject_hodlvoice(&state, json!([ph])).await.is_err());
+    assert_eq!(handle.await.unwrap(), json!({"result": "continue"}));
+}
+
+#[tokio::test]
+async fn batch_accept_reports_each_payment_hash() {
+    let lightningd = FakeLightningd::new();
+    let state = plugin_state(&lightningd);
+    let first = add(&state, "batch-1").await;
+    let second = add(&state, "batch-2").await;
+    reject_hodlvoice(&state, json!([second])).await.unwrap();
+    let unknown = "ab".repeat(32);
+
+    let result = accept_hodlvoice(&state, json!([[first, second, unknown]]))
+        .await
+        .unwrap();
+    assert_eq!(result["succeeded"], 1);
+    assert_eq!(result["failed"], 2);
+    let results = result["results"].as_array().unwrap();
+    assert_eq!(results[0]["result"], "success");
+    assert_eq!(results[0]["state"], "accepted");
+    assert_eq!(results[1]["result"], "error");
+    assert!(results[1]["error"]
+        .as_str()
+        .unwrap()
+        .contains("is already canceled"));
+    assert_eq!(results[2]["result"], "error");
+
+    let record = get_record(state.rpc.as_ref(), &first)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(record.state, Hodlstate::Accepted);
+}
+
+#[tokio::test]
+async fn single_reject_still_fails_the_call() {
+    let lightningd = FakeLightningd::new();
+    let state = plugin_state(&lightningd);
+
+    assert!(reject_hodlvoice(&state, json!(["ab".repeat(32)]))
+        .await
+        .is_err());
+    assert!(reject_hodlvoice(&state, json!([[]])).await.is_err());
+}
+
+#[tokio::test]
+async fn decide_by_label() {
+    let lightningd = FakeLightningd::new();
+    let state = plugin_state(&lightningd);
+    let first = add(&state, "order-1").await;
+    let second = add(&state, "order-2").await;
+    let third = add(&state, "order-3").await;
+
+    accept_hodlvoice(&state, json!(["order-1"])).await.unwrap();
+    reject_hodlvoice(&state, json!({"label": "order-2"}))
+        .await
+        .unwrap();
+    let result = accept_hodlvoice(&state, json!({"label": ["order-3", "order-4"]}))
+        .await
+        .unwrap();
+    assert_eq!(result["results"][0]["payment_hash"], json!(third));
+    assert_eq!(result["results"][1]["label"], "order-4");
+    assert_eq!(result["results"][1]["result"], "error");
+
+    for (ph, hodlstate) in [
+        (first, Hodlstate::Accepted),
+        (second, Hodlstate::Canceled),
+        (third, Hodlstate::Accepted),
+    ] {
+        let record = get_record(state.rpc.as_ref(), &ph).await.unwrap().unwrap();
+        assert_eq!(record.state, hodlstate);
+    }
+}
+
+#[tokio::test]
+async fn label_of_external_invoice_is_found() {
+    let lightningd = FakeLightningd::new();
+    let state = plugin_state(&lightningd);
+    let ph = "42".repeat(32);
+    add_hodlvoice(
+        &state,
+        json!({"amount_msat": 1000, "label": "external", "description": "", "payment_hash": ph}),
+    )
+    .await
+    .unwrap();
+
+    reject_hodlvoice(&state, json!({"label": "external"}))
+        .await
+        .unwrap();
+    let record = get_record(state.rpc.as_ref(), &ph).await.unwrap().unwrap();
+    assert_eq!(record.state, Hodlstate::Canceled);
+}
+
+#[tokio::test]
+async fn label_of_external_invoice_is_unique() {
+    let lightningd = FakeLightningd::new();
+    let state = plugin_state(&lightningd);
+    add(&state, "taken").await;
+    let external = |ph: String, label: &str| json!({"amount_msat": 1000, "label": label, "description": "", "payment_hash": ph});
+    add_hodlvoice(&state, external("42".repeat(32), "external"))
+        .await
+        .unwrap();
+
+    for (ph, label) in [("43".repeat(32), "taken"), ("44".repeat(32), "external")] {
+        let e = rpc_error(add_hodlvoice(&state, external(ph, label)).await);
+        assert_eq!(e.code, Some(-32602));
+    }
+    let e = rpc_error(
+        add_hodlvoice(
+            &state,
+            json!({"amount_msat": 1000, "label": "external", "description": ""}),
+        )
+        .await,
+    );
+    assert_eq!(e.code, Some(-32602));
+}